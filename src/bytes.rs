@@ -0,0 +1,91 @@
+//! Little-endian encoding and decoding of multi-byte samples, shared by the
+//! sinks and converters so the wire layout is defined in one place and
+//! doesn't depend on the host's byte order.
+//!
+//! Readers ignore a trailing incomplete sample.
+
+pub fn write_i16_le(out: &mut Vec<u8>, samples: &[i16]) {
+    out.reserve(samples.len() * 2);
+    out.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+}
+
+/// Writes the low 24 bits of each sample, packed without padding.
+pub fn write_i24_le(out: &mut Vec<u8>, samples: &[i32]) {
+    out.reserve(samples.len() * 3);
+    for sample in samples {
+        let [b0, b1, b2, _] = sample.to_le_bytes();
+        out.extend_from_slice(&[b0, b1, b2]);
+    }
+}
+
+pub fn write_f32_le(out: &mut Vec<u8>, samples: &[f32]) {
+    out.reserve(samples.len() * 4);
+    out.extend(samples.iter().flat_map(|s| s.to_le_bytes()));
+}
+
+pub fn read_i16_le(bytes: &[u8]) -> Vec<i16> {
+    bytes
+        .chunks_exact(2)
+        .map(|b| i16::from_le_bytes([b[0], b[1]]))
+        .collect()
+}
+
+/// Reads packed 24-bit samples, sign-extended to `i32`.
+pub fn read_i24_le(bytes: &[u8]) -> Vec<i32> {
+    bytes
+        .chunks_exact(3)
+        // shift into the top of an i32 so the sign bit lands in place
+        .map(|b| i32::from_le_bytes([0, b[0], b[1], b[2]]) >> 8)
+        .collect()
+}
+
+pub fn read_f32_le(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn i16_round_trip() {
+        let samples = [0, 1, -1, i16::MAX, i16::MIN, 0x1234];
+        let mut bytes = Vec::new();
+        write_i16_le(&mut bytes, &samples);
+        assert_eq!(&bytes[10..], [0x34, 0x12]);
+        assert_eq!(read_i16_le(&bytes), samples);
+    }
+
+    #[test]
+    fn i24_round_trip() {
+        let samples = [0, 1, -1, 0x7F_FFFF, -0x80_0000, 0x12_3456];
+        let mut bytes = Vec::new();
+        write_i24_le(&mut bytes, &samples);
+        assert_eq!(bytes.len(), samples.len() * 3);
+        assert_eq!(&bytes[15..], [0x56, 0x34, 0x12]);
+        assert_eq!(read_i24_le(&bytes), samples);
+    }
+
+    #[test]
+    fn f32_round_trip() {
+        let samples = [0.0, -0.0, 1.0, -1.0, 0.123, f32::MIN_POSITIVE];
+        let mut bytes = Vec::new();
+        write_f32_le(&mut bytes, &samples);
+        assert_eq!(&bytes[8..12], 1.0f32.to_le_bytes());
+        let read = read_f32_le(&bytes);
+        // compare bits so -0.0 has to survive too
+        let bits =
+            |s: &[f32]| s.iter().map(|s| s.to_bits()).collect::<Vec<_>>();
+        assert_eq!(bits(&read), bits(&samples));
+    }
+
+    #[test]
+    fn readers_ignore_incomplete_sample() {
+        assert_eq!(read_i16_le(&[1, 0, 2]), [1]);
+        assert_eq!(read_i24_le(&[1, 0, 0, 2, 0]), [1]);
+        assert_eq!(read_f32_le(&[0, 0, 0x80, 0x3F, 0]), [1.0]);
+    }
+}
@@ -0,0 +1,178 @@
+use crate::{
+    bytes::{read_f32_le, read_i16_le, read_i24_le},
+    SampleFormat,
+};
+
+/// Converts samples to 16-bit integers, clamping anything outside of
+/// `[-1.0, 1.0]`.
+pub fn f32_to_i16(samples: &[f32]) -> Vec<i16> {
+    samples.iter().map(|&s| f32_sample_to_i16(s)).collect()
+}
+
+/// Like [`f32_to_i16`], but adds TPDF (triangular) dither of ±1 LSB before
+/// rounding, which trades quantization distortion on quiet passages for a
+/// constant low noise floor.
+pub fn f32_to_i16_dithered(samples: &[f32], dither: &mut Dither) -> Vec<i16> {
+    samples
+        .iter()
+        .map(|&s| {
+            let scaled = s.clamp(-1.0, 1.0) * i16::MAX as f32;
+            let noise = dither.next_f32() - dither.next_f32();
+            (scaled + noise)
+                .round()
+                .clamp(i16::MIN as f32, i16::MAX as f32) as i16
+        })
+        .collect()
+}
+
+/// Noise source for [`f32_to_i16_dithered`]. Keep it around between calls
+/// so consecutive buffers don't repeat the same noise.
+pub struct Dither {
+    state: u32,
+}
+
+impl Dither {
+    pub fn new(seed: u32) -> Self {
+        // xorshift gets stuck on 0
+        Self { state: seed.max(1) }
+    }
+
+    /// Uniform in `[0, 1)`
+    fn next_f32(&mut self) -> f32 {
+        // xorshift32
+        self.state ^= self.state << 13;
+        self.state ^= self.state >> 17;
+        self.state ^= self.state << 5;
+        (self.state >> 8) as f32 / (1 << 24) as f32
+    }
+}
+
+impl Default for Dither {
+    fn default() -> Self {
+        Self::new(0x9E37_79B9)
+    }
+}
+
+/// Converts 16-bit integer samples to `[-1.0, 1.0)` floats.
+pub fn i16_to_f32(samples: &[i16]) -> Vec<f32> {
+    samples.iter().map(|&s| s as f32 / 32768.0).collect()
+}
+
+fn f32_sample_to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+/// Converts packed little-endian signed 24-bit samples to floats,
+/// normalized by 2^23. A trailing incomplete sample is ignored.
+pub fn i24_to_f32(bytes: &[u8]) -> Vec<f32> {
+    read_i24_le(bytes)
+        .into_iter()
+        .map(|sample| sample as f32 / (1 << 23) as f32)
+        .collect()
+}
+
+/// Sample type that raw WASAPI buffers can be converted to, see
+/// [`AudioCapture::read_samples_as`](crate::win::capture::AudioCapture::read_samples_as).
+///
+/// Integers use their full range, so e.g. 16-bit input read as `i32` ends
+/// up in the upper 16 bits.
+pub trait Sample: Sized {
+    /// Converts little-endian samples in `format`, where 8-bit PCM is
+    /// unsigned as in any wave format.
+    fn from_wasapi_bytes(format: SampleFormat, bytes: &[u8]) -> Vec<Self>;
+}
+
+impl Sample for f32 {
+    fn from_wasapi_bytes(format: SampleFormat, bytes: &[u8]) -> Vec<Self> {
+        match format {
+            SampleFormat::Int8 => {
+                bytes.iter().map(|&b| (b as f32 - 128.0) / 128.0).collect()
+            }
+            SampleFormat::Int16 => i16_to_f32(&read_i16_le(bytes)),
+            SampleFormat::Float32 => read_f32_le(bytes),
+        }
+    }
+}
+
+impl Sample for i16 {
+    fn from_wasapi_bytes(format: SampleFormat, bytes: &[u8]) -> Vec<Self> {
+        match format {
+            SampleFormat::Int8 => {
+                bytes.iter().map(|&b| (b as i16 - 128) << 8).collect()
+            }
+            SampleFormat::Int16 => read_i16_le(bytes),
+            SampleFormat::Float32 => f32_to_i16(&read_f32_le(bytes)),
+        }
+    }
+}
+
+impl Sample for i32 {
+    fn from_wasapi_bytes(format: SampleFormat, bytes: &[u8]) -> Vec<Self> {
+        match format {
+            SampleFormat::Int8 => {
+                bytes.iter().map(|&b| (b as i32 - 128) << 24).collect()
+            }
+            SampleFormat::Int16 => read_i16_le(bytes)
+                .into_iter()
+                .map(|s| (s as i32) << 16)
+                .collect(),
+            SampleFormat::Float32 => read_f32_le(bytes)
+                .into_iter()
+                .map(|s| {
+                    (s.clamp(-1.0, 1.0) as f64 * i32::MAX as f64).round() as i32
+                })
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn f32_to_i16_is_deterministic() {
+        let samples = [0.0, 1.0, -1.0, 0.5, -0.5, 2.0, -2.0];
+        let expected = [0, 32767, -32767, 16384, -16384, 32767, -32767];
+        assert_eq!(f32_to_i16(&samples), expected);
+        assert_eq!(f32_to_i16(&samples), f32_to_i16(&samples));
+    }
+
+    #[test]
+    fn dither_repeats_with_the_same_seed() {
+        let samples = vec![0.25; 64];
+        let a = f32_to_i16_dithered(&samples, &mut Dither::new(7));
+        let b = f32_to_i16_dithered(&samples, &mut Dither::new(7));
+        assert_eq!(a, b);
+    }
+
+    #[test]
+    fn dither_mean_error_is_near_zero() {
+        // a fraction of an LSB, which plain rounding turns into silence
+        let lsb = 1.0 / i16::MAX as f32;
+        let samples = vec![0.3 * lsb; 100_000];
+        assert!(f32_to_i16(&samples).iter().all(|&s| s == 0));
+
+        let dithered = f32_to_i16_dithered(&samples, &mut Dither::new(1234));
+        let mean = dithered.iter().map(|&s| s as f64).sum::<f64>()
+            / dithered.len() as f64;
+        assert!((mean - 0.3).abs() < 0.02, "mean {mean}");
+        // TPDF noise never moves a sample by more than one step
+        assert!(dithered.iter().all(|&s| (-1..=1).contains(&s)));
+    }
+
+    #[test]
+    fn i24_extremes() {
+        // 0x7FFFFF and 0x800000, little-endian
+        let samples = i24_to_f32(&[0xFF, 0xFF, 0x7F, 0x00, 0x00, 0x80]);
+        assert_eq!(samples.len(), 2);
+        assert!((samples[0] - 1.0).abs() < 1e-6, "max {}", samples[0]);
+        assert_eq!(samples[1], -1.0);
+    }
+
+    #[test]
+    fn i24_ignores_incomplete_sample() {
+        assert_eq!(i24_to_f32(&[0x00, 0x00, 0x40, 0x01, 0x02]), [0.5]);
+        assert!(i24_to_f32(&[0x01, 0x02]).is_empty());
+    }
+}
@@ -0,0 +1,840 @@
+use std::time::Duration;
+
+use crate::Format;
+
+/// Splits interleaved samples into one buffer per channel.
+///
+/// A trailing incomplete frame is dropped, so every plane has the same
+/// length.
+pub fn deinterleave(samples: &[f32], channels: u16) -> Vec<Vec<f32>> {
+    let channels = channels as usize;
+    if channels == 0 {
+        return Vec::new();
+    }
+    let frames = samples.len() / channels;
+    let mut planes = vec![Vec::with_capacity(frames); channels];
+    for frame in samples.chunks_exact(channels) {
+        for (plane, &sample) in planes.iter_mut().zip(frame) {
+            plane.push(sample);
+        }
+    }
+    planes
+}
+
+/// Inverse of [`deinterleave`]. If planes differ in length, only as many
+/// frames as the shortest plane has are produced.
+pub fn interleave(planes: &[Vec<f32>]) -> Vec<f32> {
+    let frames = planes.iter().map(Vec::len).min().unwrap_or(0);
+    let mut samples = Vec::with_capacity(frames * planes.len());
+    for i in 0..frames {
+        samples.extend(planes.iter().map(|plane| plane[i]));
+    }
+    samples
+}
+
+/// Root mean square of `samples`, 0 for an empty slice.
+pub fn rms(samples: &[f32]) -> f32 {
+    if samples.is_empty() {
+        return 0.0;
+    }
+    let sum: f32 = samples.iter().map(|s| s * s).sum();
+    (sum / samples.len() as f32).sqrt()
+}
+
+/// Largest absolute sample value, 0 for an empty slice.
+pub fn peak(samples: &[f32]) -> f32 {
+    samples.iter().fold(0.0, |peak, s| s.abs().max(peak))
+}
+
+/// Converts a linear amplitude (1.0 being full scale) to dBFS.
+pub fn to_dbfs(amplitude: f32) -> f32 {
+    20.0 * amplitude.log10()
+}
+
+/// Classifies chunks of audio as silent based on their RMS level.
+///
+/// The gate opens once a chunk reaches `threshold` dBFS, but only closes
+/// again when a chunk drops below `threshold - hysteresis`, so a signal
+/// hovering around the threshold doesn't toggle it on every chunk.
+pub struct SilenceGate {
+    threshold: f32,
+    hysteresis: f32,
+    open: bool,
+}
+
+impl SilenceGate {
+    pub fn new(threshold: f32, hysteresis: f32) -> Self {
+        Self {
+            threshold,
+            hysteresis,
+            open: false,
+        }
+    }
+
+    /// Feeds the next chunk through the gate, returning `true` if it should
+    /// be treated as silence.
+    pub fn is_silent(&mut self, samples: &[f32]) -> bool {
+        let level = to_dbfs(rms(samples));
+        if self.open {
+            self.open = level >= self.threshold - self.hysteresis;
+        } else {
+            self.open = level >= self.threshold;
+        }
+        !self.open
+    }
+}
+
+/// Scales the stereo image of interleaved stereo `samples` using mid/side
+/// processing. `width` of 0 collapses to mono, 1 leaves the signal unchanged
+/// and anything above widens it.
+///
+/// Only 2-channel audio has a stereo image, for other channel counts this
+/// does nothing.
+pub fn apply_stereo_width(samples: &mut [f32], channels: u16, width: f32) {
+    if channels != 2 {
+        return;
+    }
+    for frame in samples.chunks_exact_mut(2) {
+        let mid = (frame[0] + frame[1]) / 2.0;
+        let side = (frame[0] - frame[1]) / 2.0 * width;
+        frame[0] = mid + side;
+        frame[1] = mid - side;
+    }
+}
+
+/// Converts interleaved samples between channel counts.
+///
+/// Mono is copied to every output channel and everything is averaged when
+/// going to mono. Going to stereo from more channels uses
+/// [`downmix_to_stereo`]. Otherwise channels are matched by index, extra
+/// input channels are dropped and extra output channels are left silent.
+pub fn remix(
+    samples: &[f32],
+    from_channels: u16,
+    to_channels: u16,
+) -> Vec<f32> {
+    let (from, to) = (from_channels as usize, to_channels as usize);
+    if from == to || from == 0 || to == 0 {
+        return samples.to_vec();
+    }
+    if to == 2 && from > 2 {
+        return downmix_to_stereo(samples, from_channels);
+    }
+    let frames = samples.len() / from;
+    let mut output = Vec::with_capacity(frames * to);
+    for frame in samples.chunks_exact(from) {
+        if from == 1 {
+            output.extend(std::iter::repeat_n(frame[0], to));
+        } else if to == 1 {
+            output.push(frame.iter().sum::<f32>() / from as f32);
+        } else {
+            output
+                .extend((0..to).map(|c| frame.get(c).copied().unwrap_or(0.0)));
+        }
+    }
+    output
+}
+
+/// Duplicates every mono sample into left and right.
+pub fn upmix_mono_to_stereo(samples: &[f32]) -> Vec<f32> {
+    samples.iter().flat_map(|&s| [s, s]).collect()
+}
+
+/// Folds surround audio down to stereo with a [`Downmixer`] for the usual
+/// layout of `channels` channels, e.g. (FL, FR, FC, LFE, BL, BR, SL, SR) for
+/// 7.1. Center and surround channels are mixed in at -3dB, LFE is dropped.
+pub fn downmix_to_stereo(samples: &[f32], channels: u16) -> Vec<f32> {
+    if channels < 2 {
+        return upmix_mono_to_stereo(samples);
+    }
+    Downmixer::new(channels, 0, DownmixTarget::Stereo).process(samples)
+}
+
+/// Streaming linear-interpolation resampler for interleaved audio.
+///
+/// Keeps the last frame and the fractional read position between calls, so
+/// consecutive buffers join up without clicks or drift.
+pub struct Resampler {
+    channels: usize,
+    /// Input frames per output frame, `None` to pass audio through
+    step: Option<f64>,
+    pos: f64,
+    prev: Vec<f32>,
+}
+
+impl Resampler {
+    /// A zero rate on either side, as in a broken format, leaves the audio
+    /// unchanged.
+    pub fn new(channels: u16, from_rate: u32, to_rate: u32) -> Self {
+        let step = (from_rate != 0 && to_rate != 0)
+            .then(|| from_rate as f64 / to_rate as f64);
+        Self {
+            channels: channels as usize,
+            step,
+            // position 1 is the first frame of the first buffer
+            pos: 1.0,
+            prev: vec![0.0; channels as usize],
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let channels = self.channels;
+        if channels == 0 {
+            return Vec::new();
+        }
+        let Some(step) = self.step else {
+            return samples.to_vec();
+        };
+        let frames = samples.len() / channels;
+        if frames == 0 {
+            return Vec::new();
+        }
+        // frame 0 is `prev`, frame i > 0 is frame i - 1 of `samples`
+        let frame = |i: usize| -> &[f32] {
+            if i == 0 {
+                &self.prev
+            } else {
+                &samples[(i - 1) * channels..i * channels]
+            }
+        };
+        let mut output = Vec::with_capacity(
+            ((frames as f64 / step).ceil() as usize + 1) * channels,
+        );
+        let mut pos = self.pos;
+        while pos < frames as f64 {
+            let index = pos as usize;
+            let t = (pos - index as f64) as f32;
+            let (a, b) = (frame(index), frame(index + 1));
+            output.extend(a.iter().zip(b).map(|(a, b)| a + (b - a) * t));
+            pos += step;
+        }
+        self.pos = pos - frames as f64;
+        self.prev =
+            samples[(frames - 1) * channels..frames * channels].to_vec();
+        output
+    }
+}
+
+/// One-shot linear resampling of a complete buffer, see [`Resampler`] for
+/// streaming use. A zero rate on either side returns `samples` unchanged.
+pub fn resample_linear(
+    samples: &[f32],
+    channels: u16,
+    from_rate: u32,
+    to_rate: u32,
+) -> Vec<f32> {
+    if from_rate == to_rate || from_rate == 0 || to_rate == 0 {
+        return samples.to_vec();
+    }
+    Resampler::new(channels, from_rate, to_rate).process(samples)
+}
+
+/// How [`mix`] combines streams.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MixMode {
+    /// Plain sum, may exceed `[-1.0, 1.0]`
+    #[default]
+    Sum,
+    /// Sum divided by the number of streams, never clips but gets quieter
+    /// with every stream added
+    Average,
+    /// Sum hard-limited to `[-1.0, 1.0]`
+    Clip,
+}
+
+/// Mixes `streams` sample by sample. The result is as long as the shortest
+/// stream.
+pub fn mix(streams: &[&[f32]], mode: MixMode) -> Vec<f32> {
+    let len = streams.iter().map(|s| s.len()).min().unwrap_or(0);
+    let mut mixed = vec![0.0; len];
+    for stream in streams {
+        for (out, sample) in mixed.iter_mut().zip(*stream) {
+            *out += sample;
+        }
+    }
+    match mode {
+        MixMode::Sum => {}
+        MixMode::Average => {
+            let count = streams.len() as f32;
+            mixed.iter_mut().for_each(|s| *s /= count);
+        }
+        MixMode::Clip => mixed.iter_mut().for_each(|s| *s = s.clamp(-1.0, 1.0)),
+    }
+    mixed
+}
+
+/// Peak limiter for interleaved audio, keeping its gain between calls so
+/// reduction fades in and out smoothly across buffers.
+///
+/// All channels of a frame share one gain, so the stereo image is kept.
+/// Gain drops towards what's needed to bring the frame's peak under
+/// `ceiling` within `attack`, and recovers within `release`. Anything the
+/// attack is too slow to catch is hard-clipped, so the output never exceeds
+/// `ceiling`.
+pub struct Limiter {
+    channels: usize,
+    ceiling: f32,
+    attack: f32,
+    release: f32,
+    gain: f32,
+}
+
+impl Limiter {
+    pub fn new(
+        channels: u16,
+        sample_rate: u32,
+        ceiling: f32,
+        attack: Duration,
+        release: Duration,
+    ) -> Self {
+        // one-pole coefficient reaching ~63% of a step after `time`
+        let coefficient = |time: Duration| {
+            let samples = time.as_secs_f32() * sample_rate as f32;
+            if samples > 0.0 {
+                1.0 - (-1.0 / samples).exp()
+            } else {
+                1.0
+            }
+        };
+        Self {
+            channels: channels as usize,
+            ceiling: ceiling.abs(),
+            attack: coefficient(attack),
+            release: coefficient(release),
+            gain: 1.0,
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        let ceiling = self.ceiling;
+        for frame in samples.chunks_mut(self.channels) {
+            let peak = peak(frame);
+            let target = if peak > ceiling { ceiling / peak } else { 1.0 };
+            let coefficient = if target < self.gain {
+                self.attack
+            } else {
+                self.release
+            };
+            self.gain += (target - self.gain) * coefficient;
+            for sample in frame {
+                *sample = (*sample * self.gain).clamp(-ceiling, ceiling);
+            }
+        }
+    }
+}
+
+/// Streaming loudness normalizer steering towards a target RMS level in
+/// dBFS.
+///
+/// The level estimate is a plain RMS over everything seen so far, without
+/// K-weighting, so it approximates integrated loudness rather than
+/// measuring LUFS. Buffers below -70 dBFS don't count towards it, like the
+/// absolute gate of EBU R 128, so pauses don't drag the estimate down. The
+/// gain is ramped across each buffer to avoid zipper noise, and capped at
+/// `max_gain` dB so near-silence isn't blown up.
+pub struct LoudnessNormalizer {
+    target: f32,
+    max_gain: f32,
+    sum_squares: f64,
+    count: u64,
+    gain: f32,
+}
+
+impl LoudnessNormalizer {
+    const GATE: f32 = -70.0;
+
+    pub fn new(target: f32, max_gain: f32) -> Self {
+        Self {
+            target,
+            max_gain,
+            sum_squares: 0.0,
+            count: 0,
+            gain: 1.0,
+        }
+    }
+
+    /// Level estimate in dBFS so far, `None` until a buffer passed the gate.
+    pub fn loudness(&self) -> Option<f32> {
+        (self.count > 0).then(|| {
+            to_dbfs((self.sum_squares / self.count as f64).sqrt() as f32)
+        })
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        if samples.is_empty() {
+            return;
+        }
+        if to_dbfs(rms(samples)) >= Self::GATE {
+            self.sum_squares +=
+                samples.iter().map(|&s| s as f64 * s as f64).sum::<f64>();
+            self.count += samples.len() as u64;
+        }
+        let Some(loudness) = self.loudness() else {
+            return;
+        };
+        let gain_db = (self.target - loudness).min(self.max_gain);
+        let target_gain = 10f32.powf(gain_db / 20.0);
+        let step = (target_gain - self.gain) / samples.len() as f32;
+        for sample in samples {
+            self.gain += step;
+            *sample *= self.gain;
+        }
+        self.gain = target_gain;
+    }
+}
+
+/// Layout [`Downmixer`] mixes down to.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum DownmixTarget {
+    Mono,
+    Stereo,
+}
+
+/// Matrix downmixer for interleaved audio, e.g. 5.1 to stereo.
+///
+/// Built from the `dwChannelMask` of the source, whose set bits give the
+/// speaker of each channel in stream order, falling back to the usual
+/// layout for the channel count if the mask doesn't fit. The default
+/// coefficients follow
+/// ITU-R BS.775: center and surrounds at -3dB, LFE dropped. Mono averages
+/// left and right of that, except centered speakers which stay at unity.
+/// Use [`with_matrix`](Self::with_matrix) for anything else.
+#[derive(Debug, Clone)]
+pub struct Downmixer {
+    /// One row of input gains per output channel
+    matrix: Vec<Vec<f32>>,
+}
+
+impl Downmixer {
+    const FRONT_LEFT: u32 = 0x1;
+    const FRONT_RIGHT: u32 = 0x2;
+    const FRONT_CENTER: u32 = 0x4;
+    const LOW_FREQUENCY: u32 = 0x8;
+    const BACK_LEFT: u32 = 0x10;
+    const BACK_RIGHT: u32 = 0x20;
+    const FRONT_LEFT_OF_CENTER: u32 = 0x40;
+    const FRONT_RIGHT_OF_CENTER: u32 = 0x80;
+    const BACK_CENTER: u32 = 0x100;
+    const SIDE_LEFT: u32 = 0x200;
+    const SIDE_RIGHT: u32 = 0x400;
+
+    /// Mixes `channels` channels laid out as `channel_mask` says. If the
+    /// mask doesn't name exactly `channels` speakers, e.g. because it's 0
+    /// as for formats without one, [`default_mask`](Self::default_mask) is
+    /// used instead. Channels beyond the defined speaker positions are
+    /// dropped.
+    pub fn new(
+        channels: u16,
+        channel_mask: u32,
+        target: DownmixTarget,
+    ) -> Self {
+        const MINUS_3DB: f32 = std::f32::consts::FRAC_1_SQRT_2;
+        let channel_mask = if channel_mask.count_ones() == channels as u32 {
+            channel_mask
+        } else {
+            Self::default_mask(channels)
+        };
+        let (mut left, mut right): (Vec<f32>, Vec<f32>) = (0..32)
+            .map(|bit| 1 << bit)
+            .filter(|speaker| channel_mask & speaker != 0)
+            .map(|speaker| match speaker {
+                Self::FRONT_LEFT | Self::FRONT_LEFT_OF_CENTER => (1.0, 0.0),
+                Self::FRONT_RIGHT | Self::FRONT_RIGHT_OF_CENTER => (0.0, 1.0),
+                Self::FRONT_CENTER | Self::BACK_CENTER => {
+                    (MINUS_3DB, MINUS_3DB)
+                }
+                Self::BACK_LEFT | Self::SIDE_LEFT => (MINUS_3DB, 0.0),
+                Self::BACK_RIGHT | Self::SIDE_RIGHT => (0.0, MINUS_3DB),
+                // LFE and top speakers
+                _ => (0.0, 0.0),
+            })
+            .unzip();
+        // more channels than the mask has bits
+        left.resize(channels as usize, 0.0);
+        right.resize(channels as usize, 0.0);
+        let matrix = match target {
+            DownmixTarget::Stereo => vec![left, right],
+            DownmixTarget::Mono => vec![left
+                .iter()
+                .zip(&right)
+                .map(|(&l, &r)| {
+                    if l > 0.0 && r > 0.0 {
+                        1.0
+                    } else {
+                        (l + r) / 2.0
+                    }
+                })
+                .collect()],
+        };
+        Self { matrix }
+    }
+
+    /// Usual speaker layout for `channels` channels, the same ones Windows
+    /// defaults to. Counts without one get the first `channels` speakers in
+    /// WAVE order (FL, FR, FC, LFE, BL, BR, ...).
+    pub fn default_mask(channels: u16) -> u32 {
+        const QUAD: u32 = Downmixer::FRONT_LEFT
+            | Downmixer::FRONT_RIGHT
+            | Downmixer::BACK_LEFT
+            | Downmixer::BACK_RIGHT;
+        const SURROUND_5_1: u32 =
+            QUAD | Downmixer::FRONT_CENTER | Downmixer::LOW_FREQUENCY;
+        match channels {
+            1 => Self::FRONT_CENTER,
+            4 => QUAD,
+            6 => SURROUND_5_1,
+            8 => SURROUND_5_1 | Self::SIDE_LEFT | Self::SIDE_RIGHT,
+            _ => 1u32
+                .checked_shl(channels as u32)
+                .map_or(u32::MAX, |bit| bit - 1),
+        }
+    }
+
+    /// Uses `matrix` as is, with one row of input gains per output channel.
+    /// All rows need to be as long as the source has channels.
+    pub fn with_matrix(matrix: Vec<Vec<f32>>) -> Self {
+        Self { matrix }
+    }
+
+    pub fn matrix(&self) -> &[Vec<f32>] {
+        &self.matrix
+    }
+
+    pub fn process(&self, samples: &[f32]) -> Vec<f32> {
+        let source_channels = self.matrix.first().map_or(0, Vec::len);
+        if source_channels == 0 {
+            return Vec::new();
+        }
+        let frames = samples.chunks_exact(source_channels);
+        let mut output = Vec::with_capacity(frames.len() * self.matrix.len());
+        for frame in frames {
+            output.extend(self.matrix.iter().map(|row| {
+                row.iter().zip(frame).map(|(gain, s)| gain * s).sum::<f32>()
+            }));
+        }
+        output
+    }
+}
+
+/// One-pole high-pass filter removing DC offset from interleaved audio,
+/// keeping per-channel state between calls.
+pub struct DcBlocker {
+    /// Pole position, closer to 1 means a lower cutoff
+    r: f32,
+    prev_input: Vec<f32>,
+    prev_output: Vec<f32>,
+}
+
+impl DcBlocker {
+    /// `cutoff` in Hz, something like 10-20 Hz removes DC without touching
+    /// audible bass.
+    pub fn new(channels: u16, sample_rate: u32, cutoff: f32) -> Self {
+        let r =
+            1.0 - std::f32::consts::TAU * cutoff / sample_rate.max(1) as f32;
+        Self {
+            r: r.clamp(0.0, 1.0),
+            prev_input: vec![0.0; channels as usize],
+            prev_output: vec![0.0; channels as usize],
+        }
+    }
+
+    pub fn process(&mut self, samples: &mut [f32]) {
+        let channels = self.prev_input.len();
+        if channels == 0 {
+            return;
+        }
+        for frame in samples.chunks_mut(channels) {
+            for ((sample, prev_input), prev_output) in frame
+                .iter_mut()
+                .zip(&mut self.prev_input)
+                .zip(&mut self.prev_output)
+            {
+                let output = *sample - *prev_input + self.r * *prev_output;
+                *prev_input = *sample;
+                *prev_output = output;
+                *sample = output;
+            }
+        }
+    }
+}
+
+/// Streaming conversion of interleaved audio from one [`Format`]'s layout
+/// and rate to another's, combining [`remix`] and [`Resampler`]. Sample
+/// formats are ignored, both ends are `f32`.
+pub struct Transcoder {
+    from_channels: u16,
+    to_channels: u16,
+    /// Built once for the cases [`remix`] hands to [`downmix_to_stereo`]
+    downmixer: Option<Downmixer>,
+    resampler: Option<Resampler>,
+}
+
+impl Transcoder {
+    pub fn new(from: &Format, to: &Format) -> Self {
+        // remixing first means resampling the target channel count
+        let resampler = (from.sample_rate != to.sample_rate).then(|| {
+            Resampler::new(to.channels, from.sample_rate, to.sample_rate)
+        });
+        let downmixer = (to.channels == 2 && from.channels > 2)
+            .then(|| Downmixer::new(from.channels, 0, DownmixTarget::Stereo));
+        Self {
+            from_channels: from.channels,
+            to_channels: to.channels,
+            downmixer,
+            resampler,
+        }
+    }
+
+    pub fn process(&mut self, samples: &[f32]) -> Vec<f32> {
+        let samples = match &self.downmixer {
+            Some(downmixer) => downmixer.process(samples),
+            None => remix(samples, self.from_channels, self.to_channels),
+        };
+        match &mut self.resampler {
+            Some(resampler) => resampler.process(&samples),
+            None => samples,
+        }
+    }
+}
+
+/// Waveform of a [`ToneGenerator`].
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Waveform {
+    #[default]
+    Sine,
+    Square,
+    /// Rising sawtooth
+    Saw,
+}
+
+/// Test tone source producing interleaved samples in a [`Format`]'s layout
+/// and rate, with the same signal on every channel. The phase carries over
+/// between calls, so consecutive buffers join up seamlessly.
+pub struct ToneGenerator {
+    channels: usize,
+    step: f64,
+    amplitude: f32,
+    waveform: Waveform,
+    /// Position in the current period, `[0.0, 1.0)`
+    phase: f64,
+}
+
+impl ToneGenerator {
+    pub fn new(
+        format: &Format,
+        frequency: f32,
+        amplitude: f32,
+        waveform: Waveform,
+    ) -> Self {
+        Self {
+            channels: format.channels as usize,
+            step: frequency as f64 / format.sample_rate.max(1) as f64,
+            amplitude,
+            waveform,
+            phase: 0.0,
+        }
+    }
+
+    /// Produces the next `frames` frames.
+    pub fn generate(&mut self, frames: usize) -> Vec<f32> {
+        let mut output = Vec::with_capacity(frames * self.channels);
+        for _ in 0..frames {
+            let value = match self.waveform {
+                Waveform::Sine => (self.phase * std::f64::consts::TAU).sin(),
+                Waveform::Square => {
+                    if self.phase < 0.5 {
+                        1.0
+                    } else {
+                        -1.0
+                    }
+                }
+                Waveform::Saw => 2.0 * self.phase - 1.0,
+            } as f32
+                * self.amplitude;
+            output.extend(std::iter::repeat_n(value, self.channels));
+            self.phase = (self.phase + self.step).fract();
+        }
+        output
+    }
+
+    /// Adds the next stretch of tone to interleaved `samples`, e.g. as a
+    /// sidechain on top of captured audio.
+    pub fn mix_into(&mut self, samples: &mut [f32]) {
+        if self.channels == 0 {
+            return;
+        }
+        let tone = self.generate(samples.len() / self.channels);
+        for (sample, tone) in samples.iter_mut().zip(tone) {
+            *sample += tone;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn interleave_round_trip() {
+        let samples: Vec<f32> = (0..12).map(|i| i as f32).collect();
+        for channels in [1, 2, 3, 4, 6] {
+            let planes = deinterleave(&samples, channels);
+            assert_eq!(planes.len(), channels as usize);
+            assert_eq!(interleave(&planes), samples);
+        }
+    }
+
+    #[test]
+    fn deinterleave_splits_channels() {
+        let planes = deinterleave(&[1.0, -1.0, 2.0, -2.0, 3.0, -3.0], 2);
+        assert_eq!(planes, [vec![1.0, 2.0, 3.0], vec![-1.0, -2.0, -3.0]]);
+    }
+
+    #[test]
+    fn deinterleave_drops_incomplete_frame() {
+        let planes = deinterleave(&[1.0, 2.0, 3.0, 4.0, 5.0], 2);
+        assert_eq!(planes, [vec![1.0, 3.0], vec![2.0, 4.0]]);
+        assert_eq!(interleave(&planes), [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    #[test]
+    fn interleave_empty() {
+        assert!(deinterleave(&[1.0, 2.0], 0).is_empty());
+        assert_eq!(deinterleave(&[], 2), [Vec::<f32>::new(), Vec::new()]);
+        assert!(interleave(&[]).is_empty());
+        assert!(interleave(&[Vec::new(), Vec::new()]).is_empty());
+    }
+
+    #[test]
+    fn interleave_uses_shortest_plane() {
+        let planes = [vec![1.0, 3.0, 5.0], vec![2.0, 4.0]];
+        assert_eq!(interleave(&planes), [1.0, 2.0, 3.0, 4.0]);
+    }
+
+    const A: [f32; 4] = [0.5, 0.75, -0.75, 0.25];
+    const B: [f32; 5] = [0.25, 0.5, -0.5, 0.5, 0.125];
+
+    #[test]
+    fn mix_sum() {
+        assert_eq!(mix(&[&A, &B], MixMode::Sum), [0.75, 1.25, -1.25, 0.75]);
+    }
+
+    #[test]
+    fn mix_average() {
+        assert_eq!(
+            mix(&[&A, &B], MixMode::Average),
+            [0.375, 0.625, -0.625, 0.375]
+        );
+    }
+
+    #[test]
+    fn mix_clip() {
+        assert_eq!(mix(&[&A, &B], MixMode::Clip), [0.75, 1.0, -1.0, 0.75]);
+    }
+
+    #[test]
+    fn mix_nothing() {
+        for mode in [MixMode::Sum, MixMode::Average, MixMode::Clip] {
+            assert!(mix(&[], mode).is_empty());
+            assert!(mix(&[&A, &[]], mode).is_empty());
+        }
+    }
+
+    #[test]
+    fn limiter_keeps_ramp_under_ceiling() {
+        const CEILING: f32 = 0.8;
+        let mut limiter = Limiter::new(
+            2,
+            48000,
+            CEILING,
+            Duration::from_millis(1),
+            Duration::from_millis(50),
+        );
+        // stereo ramp from silence to 4x full scale
+        let ramp: Vec<f32> = (0..48000)
+            .flat_map(|i| {
+                let s = i as f32 / 12000.0;
+                [s, -s]
+            })
+            .collect();
+        let mut output = ramp.clone();
+        // across buffer boundaries, as in a stream
+        for buffer in output.chunks_mut(960) {
+            limiter.process(buffer);
+        }
+        assert!(peak(&output) <= CEILING);
+        // quiet samples pass untouched
+        assert_eq!(output[..1000], ramp[..1000]);
+        // one gain for the whole frame keeps the image
+        assert!(output.chunks_exact(2).all(|f| f[0] == -f[1]));
+    }
+
+    #[test]
+    fn dc_blocker_decays_constant_input() {
+        let mut blocker = DcBlocker::new(2, 48000, 20.0);
+        // a second of stereo DC at different offsets, in 10ms buffers
+        let mut output: Vec<f32> =
+            (0..48000).flat_map(|_| [0.5, -0.25]).collect();
+        for buffer in output.chunks_mut(960) {
+            blocker.process(buffer);
+        }
+        // the step passes through at first, then dies away
+        assert_eq!(output[..2], [0.5, -0.25]);
+        for channel in 0..2 {
+            let samples: Vec<f32> =
+                output.iter().skip(channel).step_by(2).copied().collect();
+            assert!(samples.windows(2).all(|w| w[1].abs() <= w[0].abs()));
+            assert!(samples[samples.len() - 1].abs() < 1e-3);
+        }
+    }
+
+    #[test]
+    fn resampler_passes_zero_rates_through() {
+        let samples: Vec<f32> = (0..8).map(|i| i as f32 / 8.0).collect();
+        assert_eq!(resample_linear(&samples, 2, 0, 48000), samples);
+        assert_eq!(resample_linear(&samples, 2, 48000, 0), samples);
+        let mut resampler = Resampler::new(2, 0, 48000);
+        assert_eq!(resampler.process(&samples), samples);
+        assert_eq!(resampler.process(&samples), samples);
+    }
+
+    #[test]
+    fn downmixer_without_mask_keeps_every_channel() {
+        for channels in [3, 5, 7] {
+            let downmixer = Downmixer::new(channels, 0, DownmixTarget::Stereo);
+            let matrix = downmixer.matrix();
+            assert!(matrix.iter().all(|row| row.len() == channels as usize));
+            // assumed to be FL, FR, FC, LFE, BL, BR, FLC, only LFE is
+            // meant to be dropped
+            for channel in (0..channels as usize).filter(|&c| c != 3) {
+                assert!(
+                    matrix.iter().any(|row| row[channel] > 0.0),
+                    "{channels} channels drop channel {channel}"
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn downmixer_ignores_mismatched_mask() {
+        // a stereo mask on 5.1 audio falls back to the 5.1 layout
+        let downmixer = Downmixer::new(6, 0x3, DownmixTarget::Stereo);
+        let frame = [0.0, 0.0, 1.0, 0.0, 0.0, 0.0];
+        let output = downmixer.process(&frame);
+        assert_eq!(output.len(), 2);
+        assert!((output[0] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+        assert_eq!(output[0], output[1]);
+    }
+
+    #[test]
+    fn downmix_to_stereo_folds_surround() {
+        let frame = [1.0, 0.0, 0.0, 1.0, 0.0, 0.0, 0.0, 1.0];
+        let output = downmix_to_stereo(&frame, 8);
+        // LFE dropped, right side at -3dB
+        assert_eq!(output[0], 1.0);
+        assert!((output[1] - std::f32::consts::FRAC_1_SQRT_2).abs() < 1e-6);
+    }
+}
@@ -1,15 +1,94 @@
+/// Forwards to `log::warn!` with the `logging` feature, otherwise only
+/// type-checks the arguments.
+macro_rules! warn {
+    ($($arg:tt)*) => {{
+        #[cfg(feature = "logging")]
+        log::warn!($($arg)*);
+        #[cfg(not(feature = "logging"))]
+        let _ = format_args!($($arg)*);
+    }};
+}
+
+use std::{fmt, time::Duration};
+
+pub mod bytes;
+pub mod convert;
+pub mod dsp;
+pub mod raw;
 pub mod win;
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "bincode", derive(bincode::Decode, bincode::Encode))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct Format {
     pub channels: u16,
     pub sample_rate: u32,
     pub sample_format: SampleFormat,
 }
 
+impl Format {
+    /// Whether samples in `self` can be used as `other` without resampling
+    /// or remixing, i.e. at most the sample format needs converting.
+    pub fn is_compatible_with(&self, other: &Format) -> bool {
+        self.channels == other.channels && self.sample_rate == other.sample_rate
+    }
+
+    /// Playback time of `frames` frames, rounded down to whole nanoseconds.
+    pub fn frames_to_duration(&self, frames: u64) -> Duration {
+        frames_to_duration(self.sample_rate, frames)
+    }
+
+    /// Number of whole frames that fit in `duration`, saturating at
+    /// `u64::MAX`.
+    pub fn duration_to_frames(&self, duration: Duration) -> u64 {
+        duration_to_frames(self.sample_rate, duration)
+    }
+}
+
+/// [`Format::frames_to_duration`] for when only the rate is at hand.
+pub(crate) fn frames_to_duration(sample_rate: u32, frames: u64) -> Duration {
+    if sample_rate == 0 {
+        return Duration::ZERO;
+    }
+    let nanos = frames as u128 * 1_000_000_000 / sample_rate as u128;
+    Duration::new(
+        (nanos / 1_000_000_000) as u64,
+        (nanos % 1_000_000_000) as u32,
+    )
+}
+
+/// [`Format::duration_to_frames`] for when only the rate is at hand.
+pub(crate) fn duration_to_frames(sample_rate: u32, duration: Duration) -> u64 {
+    let frames = duration.as_nanos() * sample_rate as u128 / 1_000_000_000;
+    u64::try_from(frames).unwrap_or(u64::MAX)
+}
+
+#[cfg(feature = "cpal")]
+impl Format {
+    /// Stream config for playing or monitoring audio in this format with
+    /// cpal, leaving the buffer size up to the device.
+    pub fn to_cpal_stream_config(&self) -> cpal::StreamConfig {
+        cpal::StreamConfig {
+            channels: self.channels,
+            sample_rate: cpal::SampleRate(self.sample_rate),
+            buffer_size: cpal::BufferSize::Default,
+        }
+    }
+}
+
+impl fmt::Display for Format {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} Hz, {} ch, {}",
+            self.sample_rate, self.channels, self.sample_format
+        )
+    }
+}
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 #[cfg_attr(feature = "bincode", derive(bincode::Decode, bincode::Encode))]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub enum SampleFormat {
     Int8,
     Int16,
@@ -25,3 +104,167 @@ impl SampleFormat {
         }
     }
 }
+
+#[cfg(feature = "cpal")]
+impl From<SampleFormat> for cpal::SampleFormat {
+    fn from(format: SampleFormat) -> Self {
+        match format {
+            // 8-bit PCM in a wave format is unsigned
+            SampleFormat::Int8 => cpal::SampleFormat::U8,
+            SampleFormat::Int16 => cpal::SampleFormat::I16,
+            SampleFormat::Float32 => cpal::SampleFormat::F32,
+        }
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl TryFrom<cpal::SampleFormat> for SampleFormat {
+    type Error = UnsupportedSampleFormat;
+
+    fn try_from(format: cpal::SampleFormat) -> Result<Self, Self::Error> {
+        match format {
+            cpal::SampleFormat::U8 => Ok(SampleFormat::Int8),
+            cpal::SampleFormat::I16 => Ok(SampleFormat::Int16),
+            cpal::SampleFormat::F32 => Ok(SampleFormat::Float32),
+            other => Err(UnsupportedSampleFormat(other)),
+        }
+    }
+}
+
+/// cpal sample format with no [`SampleFormat`] counterpart
+#[cfg(feature = "cpal")]
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct UnsupportedSampleFormat(pub cpal::SampleFormat);
+
+#[cfg(feature = "cpal")]
+impl fmt::Display for UnsupportedSampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "unsupported sample format {}", self.0)
+    }
+}
+
+#[cfg(feature = "cpal")]
+impl std::error::Error for UnsupportedSampleFormat {}
+
+impl fmt::Display for SampleFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            SampleFormat::Int8 => "Int8",
+            SampleFormat::Int16 => "Int16",
+            SampleFormat::Float32 => "Float32",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(sample_rate: u32) -> Format {
+        Format {
+            channels: 2,
+            sample_rate,
+            sample_format: SampleFormat::Float32,
+        }
+    }
+
+    #[test]
+    fn sub_millisecond_frames() {
+        let format = format(48000);
+        assert_eq!(format.frames_to_duration(0), Duration::ZERO);
+        // 20833.3ns, rounded down
+        assert_eq!(format.frames_to_duration(1), Duration::from_nanos(20833));
+        assert_eq!(format.frames_to_duration(24), Duration::from_micros(500));
+        assert_eq!(format.duration_to_frames(Duration::from_micros(500)), 24);
+    }
+
+    #[test]
+    fn sub_millisecond_durations() {
+        let format = format(48000);
+        assert_eq!(format.duration_to_frames(Duration::ZERO), 0);
+        // a frame only counts once it fits completely
+        assert_eq!(format.duration_to_frames(Duration::from_nanos(20833)), 0);
+        assert_eq!(format.duration_to_frames(Duration::from_nanos(20834)), 1);
+        assert_eq!(format.duration_to_frames(Duration::from_micros(999)), 47);
+        assert_eq!(format.duration_to_frames(Duration::from_millis(1)), 48);
+    }
+
+    #[test]
+    fn frames_round_trip_at_exact_rates() {
+        let format = format(8000);
+        for frames in [1, 7, 8000, 123_456] {
+            let duration = format.frames_to_duration(frames);
+            assert_eq!(format.duration_to_frames(duration), frames);
+        }
+    }
+
+    #[test]
+    fn zero_rate_frames() {
+        let format = format(0);
+        assert_eq!(format.frames_to_duration(48000), Duration::ZERO);
+        assert_eq!(format.duration_to_frames(Duration::from_secs(1)), 0);
+    }
+
+    #[test]
+    fn huge_durations_saturate() {
+        let format = format(u32::MAX);
+        assert_eq!(format.duration_to_frames(Duration::MAX), u64::MAX);
+        // still exact right below the limit
+        let secs = u64::MAX / u32::MAX as u64;
+        assert_eq!(
+            format.duration_to_frames(Duration::from_secs(secs)),
+            secs * u32::MAX as u64
+        );
+    }
+}
+
+#[cfg(all(test, feature = "cpal"))]
+mod cpal_tests {
+    use super::*;
+
+    #[test]
+    fn cpal_stream_config() {
+        let format = Format {
+            channels: 6,
+            sample_rate: 48000,
+            sample_format: SampleFormat::Float32,
+        };
+        let config = format.to_cpal_stream_config();
+        assert_eq!(config.channels, 6);
+        assert_eq!(config.sample_rate, cpal::SampleRate(48000));
+        assert_eq!(config.buffer_size, cpal::BufferSize::Default);
+    }
+
+    #[test]
+    fn cpal_sample_formats() {
+        let mappings = [
+            (SampleFormat::Int8, cpal::SampleFormat::U8),
+            (SampleFormat::Int16, cpal::SampleFormat::I16),
+            (SampleFormat::Float32, cpal::SampleFormat::F32),
+        ];
+        for (format, cpal_format) in mappings {
+            assert_eq!(cpal::SampleFormat::from(format), cpal_format);
+        }
+    }
+
+    #[test]
+    fn cpal_sample_formats_round_trip() {
+        for format in [
+            SampleFormat::Int8,
+            SampleFormat::Int16,
+            SampleFormat::Float32,
+        ] {
+            let cpal_format = cpal::SampleFormat::from(format);
+            assert_eq!(SampleFormat::try_from(cpal_format), Ok(format));
+        }
+    }
+
+    #[test]
+    fn cpal_u16_is_unsupported() {
+        assert_eq!(
+            SampleFormat::try_from(cpal::SampleFormat::U16),
+            Err(UnsupportedSampleFormat(cpal::SampleFormat::U16))
+        );
+    }
+}
@@ -0,0 +1,125 @@
+use std::io::{self, Write};
+
+use crate::{
+    bytes::{write_f32_le, write_i16_le},
+    convert::f32_to_i16,
+    win::capture::{Capture, ReadSamplesError},
+    Format, SampleFormat,
+};
+
+/// Writes interleaved `samples` as headerless PCM in the sample format of
+/// `format`, converting from `f32` where needed.
+///
+/// Layout per sample, frames are written channel by channel with no padding:
+/// - [`SampleFormat::Int8`]: unsigned 8-bit, silence at 128 (ffmpeg `u8`)
+/// - [`SampleFormat::Int16`]: signed 16-bit little-endian (ffmpeg `s16le`)
+/// - [`SampleFormat::Float32`]: 32-bit float little-endian (ffmpeg `f32le`)
+///
+/// Channel count and sample rate aren't stored anywhere, so the reader has
+/// to be told them separately, e.g.
+/// `ffmpeg -f f32le -ar 48000 -ac 2 -i -`.
+pub fn write_raw<W: Write>(
+    w: &mut W,
+    samples: &[f32],
+    format: &Format,
+) -> io::Result<()> {
+    match format.sample_format {
+        SampleFormat::Int8 => {
+            let bytes: Vec<u8> = samples
+                .iter()
+                .map(|&s| ((s.clamp(-1.0, 1.0) * 127.0).round() + 128.0) as u8)
+                .collect();
+            w.write_all(&bytes)
+        }
+        SampleFormat::Int16 => {
+            let mut bytes = Vec::new();
+            write_i16_le(&mut bytes, &f32_to_i16(samples));
+            w.write_all(&bytes)
+        }
+        SampleFormat::Float32 => {
+            let mut bytes = Vec::new();
+            write_f32_le(&mut bytes, samples);
+            w.write_all(&bytes)
+        }
+    }
+}
+
+/// Drains the packets currently queued in `capture` into `writer` with
+/// [`write_raw`], writing silence for packets flagged as silent. Like
+/// [`read_samples`](Capture::read_samples), this needs to be called in a
+/// loop, and `writer` can be anything from a socket to an in-memory buffer.
+pub fn stream_to_writer<C: Capture, W: Write>(
+    capture: &mut C,
+    format: &Format,
+    writer: &mut W,
+) -> Result<(), ReadSamplesError<io::Error>> {
+    let mut silence = Vec::new();
+    capture.read_samples(|data, info| {
+        if info.is_silent {
+            silence.clear();
+            silence.resize(data.len(), 0.0);
+            write_raw(writer, &silence, format)
+        } else {
+            write_raw(writer, data, format)
+        }
+    })
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use crate::win::{capture::Info, mock::MockCapture};
+
+    use super::*;
+
+    /// Streams a mono mock of `samples` in packets of 2 frames, with the
+    /// second packet flagged silent, and returns everything written
+    fn stream(samples: Vec<f32>, sample_format: SampleFormat) -> Vec<u8> {
+        let format = Format {
+            channels: 1,
+            sample_rate: 48000,
+            sample_format,
+        };
+        let mut mock = MockCapture::new(format, samples, 2);
+        mock.set_packet_info(
+            1,
+            Info {
+                is_silent: true,
+                ..Info::default()
+            },
+        );
+        mock.start().unwrap();
+        let mut out = Vec::new();
+        while !mock.is_finished() {
+            stream_to_writer(&mut mock, &format, &mut out).unwrap();
+        }
+        out
+    }
+
+    #[test]
+    fn int16_layout() {
+        let out = stream(vec![1.0, -1.0, 0.5, 0.5, 0.0], SampleFormat::Int16);
+        assert_eq!(
+            out,
+            [
+                0xFF, 0x7F, // 32767
+                0x01, 0x80, // -32767
+                0x00, 0x00, 0x00, 0x00, // silent packet
+                0x00, 0x00,
+            ]
+        );
+    }
+
+    #[test]
+    fn float32_layout() {
+        let out =
+            stream(vec![1.0, -0.5, 0.25, 0.25, 0.0], SampleFormat::Float32);
+        let mut expected = Vec::new();
+        expected.extend_from_slice(&1.0f32.to_le_bytes());
+        expected.extend_from_slice(&(-0.5f32).to_le_bytes());
+        // silent packet, both frames
+        expected.extend_from_slice(&[0; 8]);
+        expected.extend_from_slice(&0.0f32.to_le_bytes());
+        assert_eq!(out, expected);
+        assert_eq!(&out[..4], [0x00, 0x00, 0x80, 0x3F]);
+    }
+}
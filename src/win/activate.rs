@@ -0,0 +1,193 @@
+//! Per-process loopback activation through `ActivateAudioInterfaceAsync`,
+//! available since Windows 10 2004 (build 19041).
+
+use std::{
+    iter::once,
+    mem::{size_of, zeroed},
+    ptr::null_mut,
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+use winapi::{
+    ctypes::c_void,
+    shared::{
+        guiddef::REFIID,
+        minwindef::{DWORD, FALSE, ULONG},
+        winerror::{E_NOINTERFACE, HRESULT, HRESULT_FROM_WIN32, S_OK},
+        wtypes::VT_BLOB,
+    },
+    um::{
+        audioclient::IAudioClient,
+        errhandlingapi::GetLastError,
+        handleapi::CloseHandle,
+        mmdeviceapi::{
+            ActivateAudioInterfaceAsync, IActivateAudioInterfaceAsyncOperation,
+            IActivateAudioInterfaceCompletionHandler,
+            IActivateAudioInterfaceCompletionHandlerVtbl,
+        },
+        objidlbase::IAgileObject,
+        propidl::PROPVARIANT,
+        synchapi::{CreateEventW, SetEvent, WaitForSingleObject},
+        unknwnbase::{IUnknown, IUnknownVtbl},
+        winbase::INFINITE,
+        winnt::HANDLE,
+    },
+    Interface,
+};
+
+use super::common::{winapi_result, Guid, WinError};
+
+const VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK: &str = "VAD\\Process_Loopback";
+
+// not in winapi, from audioclientactivationparams.h
+const AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK: u32 = 1;
+const PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE: u32 = 0;
+
+#[repr(C)]
+struct AudioClientActivationParams {
+    activation_type: u32,
+    target_process_id: DWORD,
+    process_loopback_mode: u32,
+}
+
+/// Activates an `IAudioClient` capturing the output of process `pid` and
+/// its children, blocking until the activation completes.
+pub(crate) fn activate_process_loopback(
+    pid: u32,
+) -> Result<*mut IAudioClient, WinError> {
+    let mut params = AudioClientActivationParams {
+        activation_type: AUDIOCLIENT_ACTIVATION_TYPE_PROCESS_LOOPBACK,
+        target_process_id: pid,
+        process_loopback_mode:
+            PROCESS_LOOPBACK_MODE_INCLUDE_TARGET_PROCESS_TREE,
+    };
+    let mut prop: PROPVARIANT = unsafe { zeroed() };
+    prop.vt = VT_BLOB as _;
+    unsafe {
+        let blob = prop.data.blob_mut();
+        blob.cbSize = size_of::<AudioClientActivationParams>() as _;
+        blob.pBlobData = &mut params as *mut _ as _;
+    }
+
+    let event = unsafe { CreateEventW(null_mut(), FALSE, FALSE, null_mut()) };
+    if event.is_null() {
+        return Err(WinError::new(
+            HRESULT_FROM_WIN32(unsafe { GetLastError() }),
+            "CreateEventW",
+        ));
+    }
+    let handler = CompletionHandler::create(event);
+
+    let path: Vec<u16> = VIRTUAL_AUDIO_DEVICE_PROCESS_LOOPBACK
+        .encode_utf16()
+        .chain(once(0))
+        .collect();
+    let mut operation: *mut IActivateAudioInterfaceAsyncOperation = null_mut();
+    let result = winapi_result(
+        unsafe {
+            ActivateAudioInterfaceAsync(
+                path.as_ptr(),
+                &IAudioClient::uuidof(),
+                &mut prop,
+                handler as _,
+                &mut operation,
+            )
+        },
+        "ActivateAudioInterfaceAsync",
+    );
+    if result.is_ok() {
+        unsafe { WaitForSingleObject(event, INFINITE) };
+    }
+    unsafe {
+        CompletionHandler::release(handler as _);
+        CloseHandle(event);
+    }
+    result?;
+
+    let mut activate_result = S_OK;
+    let mut client: *mut IUnknown = null_mut();
+    let result = winapi_result(
+        unsafe {
+            (*operation).GetActivateResult(&mut activate_result, &mut client)
+        },
+        "IActivateAudioInterfaceAsyncOperation::GetActivateResult",
+    );
+    unsafe { (*operation).Release() };
+    result?;
+    winapi_result(activate_result, "ActivateAudioInterfaceAsync")?;
+    Ok(client as _)
+}
+
+/// Minimal COM object implementing `IActivateAudioInterfaceCompletionHandler`
+/// and `IAgileObject`, which signals `event` once activation completes.
+#[repr(C)]
+struct CompletionHandler {
+    vtbl: *const IActivateAudioInterfaceCompletionHandlerVtbl,
+    refs: AtomicU32,
+    event: HANDLE,
+}
+
+static COMPLETION_HANDLER_VTBL: IActivateAudioInterfaceCompletionHandlerVtbl =
+    IActivateAudioInterfaceCompletionHandlerVtbl {
+        parent: IUnknownVtbl {
+            QueryInterface: CompletionHandler::query_interface,
+            AddRef: CompletionHandler::add_ref,
+            Release: CompletionHandler::release,
+        },
+        ActivateCompleted: CompletionHandler::activate_completed,
+    };
+
+impl CompletionHandler {
+    fn create(event: HANDLE) -> *mut IActivateAudioInterfaceCompletionHandler {
+        Box::into_raw(Box::new(Self {
+            vtbl: &COMPLETION_HANDLER_VTBL,
+            refs: AtomicU32::new(1),
+            event,
+        })) as _
+    }
+
+    unsafe extern "system" fn query_interface(
+        this: *mut IUnknown,
+        riid: REFIID,
+        object: *mut *mut c_void,
+    ) -> HRESULT {
+        let iid = Guid::from_winapi(*riid);
+        if iid == Guid::from_winapi(IUnknown::uuidof())
+            || iid == Guid::from_winapi(IAgileObject::uuidof())
+            || iid
+                == Guid::from_winapi(
+                    IActivateAudioInterfaceCompletionHandler::uuidof(),
+                )
+        {
+            Self::add_ref(this);
+            *object = this as _;
+            S_OK
+        } else {
+            *object = null_mut();
+            E_NOINTERFACE
+        }
+    }
+
+    unsafe extern "system" fn add_ref(this: *mut IUnknown) -> ULONG {
+        let this = this as *mut Self;
+        (*this).refs.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    unsafe extern "system" fn release(this: *mut IUnknown) -> ULONG {
+        let this = this as *mut Self;
+        let refs = (*this).refs.fetch_sub(1, Ordering::AcqRel) - 1;
+        if refs == 0 {
+            drop(Box::from_raw(this));
+        }
+        refs
+    }
+
+    unsafe extern "system" fn activate_completed(
+        this: *mut IActivateAudioInterfaceCompletionHandler,
+        _operation: *mut IActivateAudioInterfaceAsyncOperation,
+    ) -> HRESULT {
+        let this = this as *mut Self;
+        SetEvent((*this).event);
+        S_OK
+    }
+}
@@ -1,256 +1,1819 @@
-use std::{fmt, mem::size_of, ptr::null_mut, time::Duration};
+use std::{
+    convert::Infallible,
+    ffi::OsStr,
+    fmt,
+    iter::once,
+    mem::{size_of, zeroed, ManuallyDrop},
+    ops::{ControlFlow, Deref},
+    os::windows::ffi::OsStrExt,
+    ptr::null_mut,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread::sleep,
+    time::{Duration, Instant},
+};
 
 use winapi::{
-    shared::mmreg::{
-        WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE,
-        WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM,
+    shared::{
+        ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM},
+        minwindef::FALSE,
+        mmreg::{
+            WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE,
+            WAVE_FORMAT_IEEE_FLOAT, WAVE_FORMAT_PCM,
+        },
+        winerror::{
+            ERROR_NOT_FOUND, E_INVALIDARG, HRESULT_FROM_WIN32, S_FALSE, S_OK,
+            WAIT_TIMEOUT,
+        },
+        wtypes::PROPERTYKEY,
     },
     um::{
         audioclient::{
-            IAudioCaptureClient, IAudioClient,
+            IAudioCaptureClient, IAudioClient, IAudioRenderClient,
             AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY, AUDCLNT_BUFFERFLAGS_SILENT,
-            AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR,
+            AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR, AUDCLNT_E_DEVICE_INVALIDATED,
+            AUDCLNT_E_EVENTHANDLE_NOT_SET, AUDCLNT_E_INVALID_DEVICE_PERIOD,
+            AUDCLNT_E_NOT_STOPPED, AUDCLNT_E_UNSUPPORTED_FORMAT,
         },
         audiosessiontypes::{
-            AUDCLNT_SHAREMODE_SHARED, AUDCLNT_STREAMFLAGS_LOOPBACK,
+            AUDCLNT_SHAREMODE_EXCLUSIVE, AUDCLNT_SHAREMODE_SHARED,
         },
         combaseapi::{
-            CoCreateInstance, CoTaskMemFree, CoUninitialize, CLSCTX_ALL,
+            CoCreateInstance, CoInitializeEx, CoTaskMemAlloc, CoTaskMemFree,
+            CoUninitialize, PropVariantClear, CLSCTX_ALL,
         },
+        coml2api::STGM_READ,
+        errhandlingapi::GetLastError,
+        handleapi::CloseHandle,
         mmdeviceapi::{
-            eConsole, eRender, IMMDevice, IMMDeviceEnumerator,
-            MMDeviceEnumerator,
+            eCapture, eCommunications, eConsole, eMultimedia, eRender, ERole,
+            IMMDevice, IMMDeviceEnumerator, MMDeviceEnumerator,
         },
-        objbase::CoInitialize,
+        objbase::{COINIT_APARTMENTTHREADED, COINIT_MULTITHREADED},
+        propidl::PROPVARIANT,
+        propsys::IPropertyStore,
+        synchapi::{CreateEventW, WaitForSingleObject},
+        winbase::{INFINITE, WAIT_OBJECT_0},
+        winnt::HANDLE,
     },
     Class, Interface,
 };
 
 use crate::{
-    read_unaligned,
-    win::common::{DATAFORMAT_SUBTYPE_IEEE_FLOAT, DATAFORMAT_SUBTYPE_PCM},
+    convert::Sample,
+    duration_to_frames, frames_to_duration,
+    win::common::{KnownSubtype, StreamFlags},
     Format, SampleFormat,
 };
 
-use super::common::{winapi_result, WinError};
+use super::{
+    activate::activate_process_loopback,
+    common::{
+        default_channel_mask, duration_to_reference_time, os_build,
+        reference_time_to_duration, winapi_result, Guid, PropValue,
+        WaveFormatView, WinError,
+    },
+};
+
+/// Wait before the first automatic reinit, doubled for every further attempt
+const AUTO_REINIT_BACKOFF: Duration = Duration::from_millis(100);
+const AUTO_REINIT_MAX_BACKOFF: Duration = Duration::from_secs(5);
+/// Most samples [`AudioCapture::record_for`] reserves up front, about a
+/// minute of 48 kHz stereo; longer recordings grow as they go.
+const RECORD_RESERVE_LIMIT: usize = 48000 * 2 * 60;
 
 pub struct AudioCapture {
     pub buffer_frame_size: u32,
-    pub wave_format: *mut WAVEFORMATEX,
-    pub channels: u16,
-    pub enumerator: *mut IMMDeviceEnumerator,
-    pub device: *mut IMMDevice,
-    pub client: *mut IAudioClient,
-    pub capture_client: *mut IAudioCaptureClient,
+    wave_format: *mut WAVEFORMATEX,
+    /// Of the effective format, sizes the slices handed out by
+    /// `read_samples`
+    channels: u16,
+    /// Resolved from `wave_format` once at init
+    format: Result<Format, UnknownFormat>,
+    enumerator: *mut IMMDeviceEnumerator,
+    device: *mut IMMDevice,
+    client: *mut IAudioClient,
+    capture_client: *mut IAudioCaptureClient,
+    /// Signaled by WASAPI when a buffer is ready, null unless event-driven
+    event: HANDLE,
+    keepalive: Option<RenderKeepalive>,
+    last_info: Option<Info>,
+    /// Samples read past the last full block of [`read_frames`](Self::read_frames)
+    pending_frames: PendingFrames,
+    /// Reused for packets that need `pre_gain` or `clamp` applied
+    scratch: Vec<f32>,
+    stop_token: StopToken,
+    /// Set on start with `skip_first_discontinuity`, cleared by the next
+    /// packet
+    skip_discontinuity: bool,
+    stats: CaptureStats,
+    running: bool,
+    /// What this was created from, so [`reinit`](Self::reinit) can repeat it
+    origin: Origin,
     // other library might have run CoInitialize already
     should_run_couninitalize_on_drop: bool,
 }
 
+#[derive(Debug, PartialEq, Clone)]
+enum Origin {
+    Config(CaptureConfig),
+    ProcessLoopback { pid: u32, buffer_duration: Duration },
+}
+
+impl Origin {
+    /// Same stream with a `duration` long buffer, everything else is kept.
+    fn with_buffer_duration(&self, duration: Duration) -> Self {
+        let mut origin = self.clone();
+        match &mut origin {
+            Origin::Config(config) => {
+                config.buffer_duration = duration;
+                config.buffer_frames = None;
+            }
+            Origin::ProcessLoopback {
+                buffer_duration, ..
+            } => *buffer_duration = duration,
+        }
+        origin
+    }
+}
+
+#[derive(Debug, PartialEq, Clone)]
+pub struct CaptureConfig {
+    /// Endpoint id as returned by `IMMDevice::GetId`, or the default
+    /// endpoint if `None`
+    pub device: Option<String>,
+    /// Which default endpoint to use when `device` is `None`
+    pub role: Role,
+    /// Capture what the render endpoint is playing instead of recording
+    /// from a capture endpoint (e.g. a microphone)
+    pub loopback: bool,
+    /// Let WASAPI signal an event when a buffer is ready, see
+    /// [`AudioCapture::wait`]
+    pub event_driven: bool,
+    /// Requested buffer size, [`Duration::ZERO`] lets the device pick its
+    /// default, which gives the lowest shared-mode latency. The size that was
+    /// actually allocated ends up in
+    /// [`buffer_frame_size`](AudioCapture::buffer_frame_size).
+    pub buffer_duration: Duration,
+    /// Buffer size in frames of the capture format, overrides
+    /// `buffer_duration` if set
+    pub buffer_frames: Option<u32>,
+    /// Play silence on the loopback endpoint while capturing. Some systems
+    /// deliver no packets at all when nothing is playing, keeping a render
+    /// stream open forces the endpoint to stay active. Ignored unless
+    /// `loopback` is set.
+    pub keepalive_render: bool,
+    /// Flags passed on top of the ones implied by the other options, for
+    /// advanced use
+    pub stream_flags: StreamFlags,
+    /// COM apartment to initialize the calling thread with, if it isn't
+    /// already
+    pub com_threading: ComThreading,
+    /// Format to request instead of the device's mix format. WASAPI's
+    /// converter resamples and remixes to it, which only works in shared
+    /// mode (the only mode this crate opens streams in).
+    ///
+    /// Must use [`SampleFormat::Float32`], since
+    /// [`read_samples`](AudioCapture::read_samples) hands out `f32` samples.
+    pub target_format: Option<Format>,
+    /// How many times the readers may [`reinit`](AudioCapture::reinit) the
+    /// stream when the device gets invalidated, e.g. across suspend/resume,
+    /// before giving up and returning the error. 0 disables this.
+    ///
+    /// Covers [`read_samples`](AudioCapture::read_samples) and everything
+    /// built on it, [`read_samples_as`](AudioCapture::read_samples_as),
+    /// [`read_samples_dual`](AudioCapture::read_samples_dual),
+    /// [`drain`](AudioCapture::drain) and
+    /// [`try_read_packet`](AudioCapture::try_read_packet).
+    pub auto_reinit: u32,
+    /// Drop the first packet after [`start`](AudioCapture::start) if it's
+    /// flagged as a discontinuity, which on loopback streams is often a
+    /// priming burst that starts recordings with a click. Delays the first
+    /// real samples by one packet when it kicks in.
+    pub skip_first_discontinuity: bool,
+    /// Linear gain applied to `f32` samples before callbacks see them, e.g.
+    /// to tame hot loopback sources. This includes the decoded view of
+    /// [`read_samples_dual`](AudioCapture::read_samples_dual), but not its
+    /// bytes or other raw-bytes readers like
+    /// [`read_samples_as`](AudioCapture::read_samples_as).
+    pub pre_gain: f32,
+    /// Clamp `f32` samples to `[-1.0, 1.0]` after `pre_gain`, which can push
+    /// them past full scale.
+    pub clamp: bool,
+}
+
+impl Default for CaptureConfig {
+    fn default() -> Self {
+        Self {
+            device: None,
+            role: Role::default(),
+            loopback: true,
+            event_driven: false,
+            buffer_duration: Duration::from_millis(100),
+            buffer_frames: None,
+            keepalive_render: false,
+            stream_flags: StreamFlags::empty(),
+            com_threading: ComThreading::default(),
+            target_format: None,
+            auto_reinit: 0,
+            skip_first_discontinuity: false,
+            pre_gain: 1.0,
+            clamp: false,
+        }
+    }
+}
+
+/// Role of the default endpoint, Windows lets users pick a different default
+/// device for each of them.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum Role {
+    #[default]
+    Console,
+    Multimedia,
+    Communications,
+}
+
+impl From<Role> for ERole {
+    fn from(role: Role) -> Self {
+        match role {
+            Role::Console => eConsole,
+            Role::Multimedia => eMultimedia,
+            Role::Communications => eCommunications,
+        }
+    }
+}
+
+/// Threading model passed to `CoInitializeEx`.
+///
+/// `Apartment` (STA) is what plain `CoInitialize` gives and suits UI
+/// threads. `Multithreaded` (MTA) is a better fit for servers
+/// and worker threads, and is the apartment WASAPI invokes notification
+/// callbacks from anyway, so objects shared with them don't need
+/// marshaling. If the thread already has COM initialized, the existing
+/// apartment is kept and left alone on drop.
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ComThreading {
+    #[default]
+    Apartment,
+    Multithreaded,
+}
+
+/// Initializes COM on the calling thread, returning whether the matching
+/// `CoUninitialize` is ours to call.
+fn co_initialize(threading: ComThreading) -> bool {
+    let coinit = match threading {
+        ComThreading::Apartment => COINIT_APARTMENTTHREADED,
+        ComThreading::Multithreaded => COINIT_MULTITHREADED,
+    };
+    winapi_result(
+        unsafe { CoInitializeEx(null_mut(), coinit) },
+        "CoInitializeEx",
+    )
+    .is_ok()
+}
+
+#[derive(Default)]
+pub struct AudioCaptureBuilder {
+    config: CaptureConfig,
+}
+
+impl AudioCaptureBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn device(mut self, id: impl Into<String>) -> Self {
+        self.config.device = Some(id.into());
+        self
+    }
+
+    pub fn role(mut self, role: Role) -> Self {
+        self.config.role = role;
+        self
+    }
+
+    pub fn loopback(mut self, loopback: bool) -> Self {
+        self.config.loopback = loopback;
+        self
+    }
+
+    pub fn event_driven(mut self, event_driven: bool) -> Self {
+        self.config.event_driven = event_driven;
+        self
+    }
+
+    pub fn buffer_duration(mut self, buffer_duration: Duration) -> Self {
+        self.config.buffer_duration = buffer_duration;
+        self
+    }
+
+    /// Sizes the buffer in frames instead of time, converted using the
+    /// sample rate of the capture format. Must be at least one device period.
+    pub fn buffer_frames(mut self, frames: u32) -> Self {
+        self.config.buffer_frames = Some(frames);
+        self
+    }
+
+    pub fn keepalive_render(mut self, keepalive_render: bool) -> Self {
+        self.config.keepalive_render = keepalive_render;
+        self
+    }
+
+    pub fn stream_flags(mut self, stream_flags: StreamFlags) -> Self {
+        self.config.stream_flags = stream_flags;
+        self
+    }
+
+    pub fn pre_gain(mut self, gain: f32) -> Self {
+        self.config.pre_gain = gain;
+        self
+    }
+
+    pub fn clamp(mut self, clamp: bool) -> Self {
+        self.config.clamp = clamp;
+        self
+    }
+
+    pub fn skip_first_discontinuity(mut self, skip: bool) -> Self {
+        self.config.skip_first_discontinuity = skip;
+        self
+    }
+
+    pub fn auto_reinit(mut self, attempts: u32) -> Self {
+        self.config.auto_reinit = attempts;
+        self
+    }
+
+    pub fn com_threading(mut self, com_threading: ComThreading) -> Self {
+        self.config.com_threading = com_threading;
+        self
+    }
+
+    pub fn target_format(mut self, format: Format) -> Self {
+        self.config.target_format = Some(format);
+        self
+    }
+
+    pub fn build(self) -> Result<AudioCapture, WinError> {
+        AudioCapture::init_with_config(self.config)
+    }
+}
+
 impl AudioCapture {
+    pub fn builder() -> AudioCaptureBuilder {
+        AudioCaptureBuilder::new()
+    }
+
     pub fn init(buffer_duration: Duration) -> Result<Self, WinError> {
-        let should_run_couninitilize_on_drop =
-            winapi_result(unsafe { CoInitialize(null_mut()) }).is_ok();
+        Self::builder().buffer_duration(buffer_duration).build()
+    }
 
-        let mut enumerator: *mut IMMDeviceEnumerator = null_mut();
-        winapi_result(unsafe {
-            CoCreateInstance(
-                &MMDeviceEnumerator::uuidof(),
-                null_mut(),
-                CLSCTX_ALL,
-                &IMMDeviceEnumerator::uuidof(),
-                &mut enumerator as *mut _ as _,
-            )
-        })?;
+    pub fn init_with_config(config: CaptureConfig) -> Result<Self, WinError> {
+        let CaptureConfig {
+            device: device_id,
+            role,
+            loopback,
+            event_driven,
+            buffer_duration,
+            buffer_frames,
+            keepalive_render,
+            stream_flags: extra_stream_flags,
+            com_threading,
+            target_format,
+            auto_reinit: _,
+            skip_first_discontinuity: _,
+            pre_gain: _,
+            clamp: _,
+        } = config.clone();
 
-        let mut device: *mut IMMDevice = null_mut();
-        winapi_result(unsafe {
-            (&*enumerator).GetDefaultAudioEndpoint(
-                eRender,
-                eConsole,
-                &mut device,
-            )
-        })?;
+        let buffer_reference_time =
+            duration_to_reference_time(buffer_duration)?;
 
-        let mut client: *mut IAudioClient = null_mut();
-        winapi_result(unsafe {
-            (&*device).Activate(
-                &IAudioClient::uuidof(),
-                CLSCTX_ALL,
-                null_mut(),
-                &mut client as *mut _ as _,
-            )
-        })?;
+        // before Windows 10 the event is never signaled for loopback streams
+        if loopback && event_driven && os_build().is_some_and(|b| b < 10240) {
+            return Err(WinError::unsupported(
+                "event-driven loopback needs Windows 10, poll instead",
+            ));
+        }
 
-        let mut wave_format: *mut WAVEFORMATEX = null_mut();
-        winapi_result(unsafe { (&*client).GetMixFormat(&mut wave_format) })
-            .unwrap();
-
-        let channels = unsafe { read_unaligned!(wave_format.nChannels) };
-
-        // 100ns unit
-        let dur = (buffer_duration.as_secs() as i64)
-            .checked_mul(100_000_000_000)
-            .expect("duration math overflow")
-            .checked_add(buffer_duration.subsec_nanos() as i64 * 100)
-            .expect("duration math overflow");
-        winapi_result(unsafe {
-            (&*client).Initialize(
-                AUDCLNT_SHAREMODE_SHARED,
-                AUDCLNT_STREAMFLAGS_LOOPBACK,
-                dur,
-                0,
-                wave_format,
-                null_mut(),
-            )
-        })
-        .unwrap();
+        let mut init = PartialInit::new(co_initialize(com_threading));
+
+        winapi_result(
+            unsafe {
+                CoCreateInstance(
+                    &MMDeviceEnumerator::uuidof(),
+                    null_mut(),
+                    CLSCTX_ALL,
+                    &IMMDeviceEnumerator::uuidof(),
+                    &mut init.enumerator as *mut _ as _,
+                )
+            },
+            "CoCreateInstance",
+        )?;
+
+        match device_id {
+            Some(id) => {
+                let id: Vec<u16> =
+                    OsStr::new(&id).encode_wide().chain(once(0)).collect();
+                winapi_result(
+                    unsafe {
+                        (&*init.enumerator)
+                            .GetDevice(id.as_ptr(), &mut init.device)
+                    },
+                    "IMMDeviceEnumerator::GetDevice",
+                )?;
+            }
+            None => {
+                let data_flow = if loopback { eRender } else { eCapture };
+                winapi_result(
+                    unsafe {
+                        (&*init.enumerator).GetDefaultAudioEndpoint(
+                            data_flow,
+                            role.into(),
+                            &mut init.device,
+                        )
+                    },
+                    "IMMDeviceEnumerator::GetDefaultAudioEndpoint",
+                )?;
+            }
+        }
+
+        winapi_result(
+            unsafe {
+                (&*init.device).Activate(
+                    &IAudioClient::uuidof(),
+                    CLSCTX_ALL,
+                    null_mut(),
+                    &mut init.client as *mut _ as _,
+                )
+            },
+            "IMMDevice::Activate",
+        )?;
+        let client = init.client;
+
+        winapi_result(
+            unsafe { (&*client).GetMixFormat(&mut init.wave_format) },
+            "IAudioClient::GetMixFormat",
+        )?;
+
+        let mut stream_flags = extra_stream_flags;
+        if loopback {
+            stream_flags |= StreamFlags::LOOPBACK;
+        }
+        if event_driven {
+            stream_flags |= StreamFlags::EVENTCALLBACK;
+        }
+        if let Some(target_format) = target_format {
+            if target_format.sample_format != SampleFormat::Float32 {
+                return Err(WinError::new(
+                    AUDCLNT_E_UNSUPPORTED_FORMAT,
+                    "IAudioClient::IsFormatSupported",
+                ));
+            }
+            let target = alloc_wave_format(&target_format);
+            let mut closest_match: *mut WAVEFORMATEX = null_mut();
+            let hresult = unsafe {
+                (&*client).IsFormatSupported(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    target,
+                    &mut closest_match,
+                )
+            };
+            unsafe {
+                CoTaskMemFree(closest_match as _);
+                CoTaskMemFree(init.wave_format as _);
+            }
+            init.wave_format = target;
+            // S_FALSE only means WASAPI would have picked a different
+            // format, the converter takes care of the difference
+            if hresult != S_FALSE {
+                winapi_result(hresult, "IAudioClient::IsFormatSupported")?;
+            }
+            stream_flags |=
+                StreamFlags::AUTOCONVERTPCM | StreamFlags::SRC_DEFAULT_QUALITY;
+        }
+
+        let wave_format = init.wave_format;
+        let view = unsafe { WaveFormatView::new(wave_format) };
+        let channels = view.channels();
+
+        let dur = match buffer_frames {
+            Some(frames) => {
+                let sample_rate = view.sample_rate();
+                if sample_rate == 0 {
+                    return Err(WinError::new(
+                        AUDCLNT_E_UNSUPPORTED_FORMAT,
+                        "AudioCaptureBuilder::buffer_frames",
+                    ));
+                }
+                let dur = duration_to_reference_time(frames_to_duration(
+                    sample_rate,
+                    frames as u64,
+                ))?;
+                let mut min_period = 0;
+                winapi_result(
+                    unsafe {
+                        (&*client).GetDevicePeriod(null_mut(), &mut min_period)
+                    },
+                    "IAudioClient::GetDevicePeriod",
+                )?;
+                if dur < min_period {
+                    return Err(WinError::new(
+                        AUDCLNT_E_INVALID_DEVICE_PERIOD,
+                        "AudioCaptureBuilder::buffer_frames",
+                    ));
+                }
+                dur
+            }
+            // zero asks WASAPI for the default periodicity
+            None => buffer_reference_time,
+        };
+        winapi_result(
+            unsafe {
+                (&*client).Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    stream_flags.bits(),
+                    dur,
+                    0,
+                    wave_format,
+                    null_mut(),
+                )
+            },
+            "IAudioClient::Initialize",
+        )?;
+
+        if event_driven {
+            init.event =
+                unsafe { CreateEventW(null_mut(), FALSE, FALSE, null_mut()) };
+            if init.event.is_null() {
+                return Err(WinError::new(
+                    HRESULT_FROM_WIN32(unsafe { GetLastError() }),
+                    "CreateEventW",
+                ));
+            }
+            winapi_result(
+                unsafe { (&*client).SetEventHandle(init.event) },
+                "IAudioClient::SetEventHandle",
+            )?;
+        }
 
         let mut buffer_frame_size = 0;
-        winapi_result(unsafe {
-            (&*client).GetBufferSize(&mut buffer_frame_size)
-        })
-        .unwrap();
-
-        let mut capture_client: *mut IAudioCaptureClient = null_mut();
-        winapi_result(unsafe {
-            (&*client).GetService(
-                &IAudioCaptureClient::uuidof(),
-                &mut capture_client as *mut _ as _,
-            )
+        winapi_result(
+            unsafe { (&*client).GetBufferSize(&mut buffer_frame_size) },
+            "IAudioClient::GetBufferSize",
+        )?;
+
+        winapi_result(
+            unsafe {
+                (&*client).GetService(
+                    &IAudioCaptureClient::uuidof(),
+                    &mut init.capture_client as *mut _ as _,
+                )
+            },
+            "IAudioClient::GetService",
+        )?;
+
+        let keepalive = if loopback && keepalive_render {
+            Some(RenderKeepalive::init(init.device, dur)?)
+        } else {
+            None
+        };
+
+        let init = ManuallyDrop::new(init);
+        Ok(Self {
+            buffer_frame_size,
+            wave_format,
+            channels,
+            format: unsafe { Format::from_wave_format(wave_format) },
+            enumerator: init.enumerator,
+            device: init.device,
+            client,
+            capture_client: init.capture_client,
+            event: init.event,
+            keepalive,
+            last_info: None,
+            pending_frames: PendingFrames::default(),
+            scratch: Vec::new(),
+            stop_token: StopToken::default(),
+            skip_discontinuity: false,
+            stats: CaptureStats::default(),
+            running: false,
+            origin: Origin::Config(config),
+            should_run_couninitalize_on_drop: init.should_run_couninitialize,
         })
-        .unwrap();
+    }
+
+    /// Captures only the audio played by process `pid` and its child
+    /// processes, as 48kHz stereo `f32`. Requires Windows 10 2004 or newer.
+    /// A zero `buffer_duration` uses the default buffer size.
+    pub fn init_process_loopback(
+        pid: u32,
+        buffer_duration: Duration,
+    ) -> Result<Self, WinError> {
+        if os_build().is_some_and(|build| build < 19041) {
+            return Err(WinError::unsupported(
+                "process loopback needs Windows 10 2004 (build 19041), \
+                 capture the whole endpoint instead",
+            ));
+        }
+
+        let buffer_reference_time =
+            duration_to_reference_time(buffer_duration)?;
+
+        let mut init = PartialInit::new(co_initialize(ComThreading::default()));
+
+        init.client = activate_process_loopback(pid)?;
+        let client = init.client;
+
+        // process loopback clients don't implement GetMixFormat, so the
+        // format has to be picked up front
+        init.wave_format = alloc_wave_format(&Format {
+            channels: 2,
+            sample_rate: 48000,
+            sample_format: SampleFormat::Float32,
+        });
+        let wave_format = init.wave_format;
+        let channels = 2;
+
+        winapi_result(
+            unsafe {
+                (&*client).Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    (StreamFlags::LOOPBACK
+                        | StreamFlags::AUTOCONVERTPCM
+                        | StreamFlags::SRC_DEFAULT_QUALITY)
+                        .bits(),
+                    buffer_reference_time,
+                    0,
+                    wave_format,
+                    null_mut(),
+                )
+            },
+            "IAudioClient::Initialize",
+        )?;
+
+        let mut buffer_frame_size = 0;
+        winapi_result(
+            unsafe { (&*client).GetBufferSize(&mut buffer_frame_size) },
+            "IAudioClient::GetBufferSize",
+        )?;
+
+        winapi_result(
+            unsafe {
+                (&*client).GetService(
+                    &IAudioCaptureClient::uuidof(),
+                    &mut init.capture_client as *mut _ as _,
+                )
+            },
+            "IAudioClient::GetService",
+        )?;
 
+        let init = ManuallyDrop::new(init);
         Ok(Self {
             buffer_frame_size,
             wave_format,
             channels,
-            enumerator,
-            device,
+            format: unsafe { Format::from_wave_format(wave_format) },
+            enumerator: null_mut(),
+            device: null_mut(),
             client,
-            capture_client,
-            should_run_couninitalize_on_drop: should_run_couninitilize_on_drop,
+            capture_client: init.capture_client,
+            event: null_mut(),
+            keepalive: None,
+            last_info: None,
+            pending_frames: PendingFrames::default(),
+            scratch: Vec::new(),
+            stop_token: StopToken::default(),
+            skip_discontinuity: false,
+            stats: CaptureStats::default(),
+            running: false,
+            origin: Origin::ProcessLoopback {
+                pid,
+                buffer_duration,
+            },
+            should_run_couninitalize_on_drop: init.should_run_couninitialize,
         })
     }
 
-    pub fn format(&self) -> Result<Format, UnknownFormat> {
-        let wave_format = self.wave_format;
+    /// Releases and recreates the stream with the same options it was
+    /// originally created with, e.g. after the device was invalidated. The
+    /// endpoint is looked up again, so a new default device gets picked up.
+    /// The stream is restarted if it was running.
+    pub fn reinit(&mut self) -> Result<(), WinError> {
+        reinit_staged(self).map_err(|e| match e {
+            ReinitError::Init(e) | ReinitError::Start(e) => e,
+        })
+    }
+
+    /// Recreates the stream with a new buffer size, keeping the device and
+    /// every other option, e.g. for a latency slider. WASAPI can't resize a
+    /// buffer in place, so this goes through [`reinit`](Self::reinit):
+    /// queued samples are lost and the stream is restarted if it was
+    /// running. Overrides a [`buffer_frames`](CaptureConfig::buffer_frames)
+    /// setting. Callbacks can't call this, as they don't have access to the
+    /// capture.
+    pub fn set_buffer_duration(
+        &mut self,
+        duration: Duration,
+    ) -> Result<(), WinError> {
+        set_buffer_duration(self, duration)
+    }
 
-        let channels;
-        let sample_rate;
-        let sample_format;
+    /// Channel count of the effective format, which is the target format
+    /// if one was requested.
+    pub fn channels(&self) -> u16 {
+        self.channels
+    }
+
+    /// Reads property `key` of the captured endpoint, e.g.
+    /// [`PKEY_Device_FriendlyName`](super::common::PKEY_Device_FriendlyName)
+    /// or [`PKEY_AudioEndpoint_FormFactor`](super::common::PKEY_AudioEndpoint_FormFactor).
+    ///
+    /// Process loopback captures have no endpoint and fail with
+    /// `ERROR_NOT_FOUND`.
+    pub fn device_property(
+        &self,
+        key: PROPERTYKEY,
+    ) -> Result<PropValue, WinError> {
+        if self.device.is_null() {
+            return Err(WinError::new(
+                HRESULT_FROM_WIN32(ERROR_NOT_FOUND),
+                "AudioCapture::device_property",
+            ));
+        }
+        let mut store: *mut IPropertyStore = null_mut();
+        winapi_result(
+            unsafe { (*self.device).OpenPropertyStore(STGM_READ, &mut store) },
+            "IMMDevice::OpenPropertyStore",
+        )?;
+        let mut prop: PROPVARIANT = unsafe { zeroed() };
+        let result = winapi_result(
+            unsafe { (*store).GetValue(&key, &mut prop) },
+            "IPropertyStore::GetValue",
+        );
+        let value =
+            result.map(|_| unsafe { PropValue::from_propvariant(&prop) });
         unsafe {
-            let sample_bitsize = read_unaligned!(wave_format.wBitsPerSample);
-            let struct_size = read_unaligned!(wave_format.cbSize);
-            let format_tag = read_unaligned!(wave_format.wFormatTag);
-            sample_format = match (format_tag, sample_bitsize) {
-                (WAVE_FORMAT_PCM, 8) => Some(SampleFormat::Int8),
-                (WAVE_FORMAT_PCM, 16) => Some(SampleFormat::Int16),
-                (WAVE_FORMAT_IEEE_FLOAT, 32) => Some(SampleFormat::Float32),
-                (WAVE_FORMAT_EXTENSIBLE, _)
-                    if size_of::<WAVEFORMATEXTENSIBLE>()
-                        - size_of::<WAVEFORMATEX>()
-                        == struct_size as usize =>
-                {
-                    let wave_format: *mut WAVEFORMATEXTENSIBLE =
-                        wave_format as _;
-                    let format_guid = read_unaligned!(wave_format.SubFormat);
-                    match (format_guid.into(), sample_bitsize) {
-                        (DATAFORMAT_SUBTYPE_PCM, 8) => Some(SampleFormat::Int8),
-                        (DATAFORMAT_SUBTYPE_PCM, 16) => {
-                            Some(SampleFormat::Int16)
-                        }
-                        (DATAFORMAT_SUBTYPE_IEEE_FLOAT, 32) => {
-                            Some(SampleFormat::Float32)
+            PropVariantClear(&mut prop);
+            (*store).Release();
+        }
+        value
+    }
+
+    /// Whether this captures what an endpoint or process plays, as opposed
+    /// to a real input like a microphone.
+    pub fn is_loopback(&self) -> bool {
+        match &self.origin {
+            Origin::Config(config) => config.loopback,
+            Origin::ProcessLoopback { .. } => true,
+        }
+    }
+
+    /// Maximum latency of the stream as reported by
+    /// `IAudioClient::GetStreamLatency`, not counting the buffer.
+    pub fn stream_latency(&self) -> Result<Duration, WinError> {
+        let mut latency = 0;
+        winapi_result(
+            unsafe { (*self.client).GetStreamLatency(&mut latency) },
+            "IAudioClient::GetStreamLatency",
+        )?;
+        Ok(reference_time_to_duration(latency))
+    }
+
+    /// Rough worst case from a sound being played to it being read:
+    /// [`stream_latency`](Self::stream_latency) plus a full buffer. Polling
+    /// less often than the buffer duration adds to this.
+    pub fn latency_estimate(&self) -> Result<Duration, WinError> {
+        let buffer = frames_to_duration(
+            self.wave_format_view().sample_rate(),
+            self.buffer_frame_size as u64,
+        );
+        Ok(self.stream_latency()? + buffer)
+    }
+
+    /// `nAvgBytesPerSec` as reported by the OS, which should be preferred
+    /// over recomputing it from [`format`](Self::format).
+    pub fn avg_bytes_per_sec(&self) -> u32 {
+        self.wave_format_view().avg_bytes_per_sec()
+    }
+
+    /// The `IAudioClient` behind this capture, e.g. for `GetService` with
+    /// interfaces this crate doesn't wrap, like `IAudioClock`.
+    ///
+    /// # Safety
+    /// The pointer is owned by the capture and only valid until it's dropped
+    /// or [`reinit`](Self::reinit)ialized. Callers must not release it
+    /// without a matching `AddRef`, and must not change stream state (start,
+    /// stop, reset) behind the capture's back.
+    pub unsafe fn audio_client_ptr(&self) -> *mut IAudioClient {
+        self.client
+    }
+
+    /// The `IAudioCaptureClient`, under the same rules as
+    /// [`audio_client_ptr`](Self::audio_client_ptr). Getting or releasing
+    /// buffers through it desynchronizes [`stats`](Self::stats).
+    ///
+    /// # Safety
+    /// See [`audio_client_ptr`](Self::audio_client_ptr).
+    pub unsafe fn capture_client_ptr(&self) -> *mut IAudioCaptureClient {
+        self.capture_client
+    }
+
+    /// The captured endpoint, under the same rules as
+    /// [`audio_client_ptr`](Self::audio_client_ptr). Null for process
+    /// loopback captures.
+    ///
+    /// # Safety
+    /// See [`audio_client_ptr`](Self::audio_client_ptr).
+    pub unsafe fn device_ptr(&self) -> *mut IMMDevice {
+        self.device
+    }
+
+    /// The effective format as reported by WASAPI.
+    pub fn wave_format_view(&self) -> WaveFormatView<'_> {
+        // valid until drop, `wave_format` is private and only replaced along
+        // with `self`
+        unsafe { WaveFormatView::new(self.wave_format) }
+    }
+
+    /// Speaker layout as a `KSAUDIO_SPEAKER_*` style mask, one bit per
+    /// channel in stream order. Formats that don't carry a mask get the
+    /// usual layout for their channel count, see [`default_channel_mask`].
+    pub fn channel_mask(&self) -> u32 {
+        self.wave_format_view()
+            .channel_mask()
+            .unwrap_or_else(|| default_channel_mask(self.channels))
+    }
+
+    /// Format captured samples are in, as resolved at init.
+    pub fn format(&self) -> Result<Format, UnknownFormat> {
+        self.format.clone()
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running
+    }
+
+    /// Starts the stream. Does nothing if it's already running.
+    pub fn start(&mut self) -> Result<(), WinError> {
+        if !self.running {
+            if let Some(keepalive) = &self.keepalive {
+                keepalive.start()?;
+            }
+            let started = winapi_result(
+                unsafe { (*self.client).Start() },
+                "IAudioClient::Start",
+            );
+            if started.is_err() {
+                // don't leave the render stream playing for nothing, the
+                // Start error is the one worth reporting
+                if let Some(keepalive) = &self.keepalive {
+                    let _ = keepalive.stop();
+                }
+            }
+            started?;
+            self.running = true;
+            self.skip_discontinuity = matches!(
+                &self.origin,
+                Origin::Config(config) if config.skip_first_discontinuity
+            );
+        }
+        Ok(())
+    }
+
+    /// Starts the stream, returning a guard that stops it again when dropped.
+    pub fn activate(&mut self) -> Result<ActiveCapture<'_>, WinError> {
+        self.start()?;
+        Ok(ActiveCapture { capture: self })
+    }
+
+    /// Stops the stream. Does nothing if it isn't running.
+    pub fn stop(&mut self) -> Result<(), WinError> {
+        if self.running {
+            winapi_result(
+                unsafe { (*self.client).Stop() },
+                "IAudioClient::Stop",
+            )?;
+            if let Some(keepalive) = &self.keepalive {
+                keepalive.stop()?;
+            }
+            self.running = false;
+        }
+        Ok(())
+    }
+
+    /// Drops any audio still queued in the stream, so a following
+    /// [`start`](Self::start) doesn't deliver stale data. Must only be called
+    /// while stopped.
+    pub fn reset(&mut self) -> Result<(), WinError> {
+        if self.running {
+            return Err(WinError::new(
+                AUDCLNT_E_NOT_STOPPED,
+                "IAudioClient::Reset",
+            ));
+        }
+        let hresult = unsafe { (*self.client).Reset() };
+        // S_FALSE means there was nothing to drop
+        if hresult == S_FALSE {
+            return Ok(());
+        }
+        winapi_result(hresult, "IAudioClient::Reset")
+    }
+
+    /// Event WASAPI signals when a buffer is ready, for waiting on it
+    /// together with other handles, e.g. in `WaitForMultipleObjects`.
+    /// `None` unless event-driven.
+    ///
+    /// The handle is owned by the capture: it must not be closed, and it
+    /// becomes invalid once the capture is dropped or
+    /// [`reinit`](Self::reinit)ialized.
+    pub fn event_handle(&self) -> Option<HANDLE> {
+        (!self.event.is_null()).then_some(self.event)
+    }
+
+    /// Blocks until WASAPI signals that a buffer is ready or `timeout`
+    /// passes, returning `false` on timeout. Only available for
+    /// event-driven captures.
+    pub fn wait(&self, timeout: Duration) -> Result<bool, WinError> {
+        if self.event.is_null() {
+            return Err(WinError::new(
+                AUDCLNT_E_EVENTHANDLE_NOT_SET,
+                "AudioCapture::wait",
+            ));
+        }
+        let timeout = timeout.as_millis().min(INFINITE as u128 - 1) as u32;
+        match unsafe { WaitForSingleObject(self.event, timeout) } {
+            WAIT_OBJECT_0 => Ok(true),
+            WAIT_TIMEOUT => Ok(false),
+            _ => Err(WinError::new(
+                HRESULT_FROM_WIN32(unsafe { GetLastError() }),
+                "WaitForSingleObject",
+            )),
+        }
+    }
+
+    /// Starts the stream, collects `duration` worth of interleaved samples
+    /// and stops it again.
+    ///
+    /// The amount is counted in frames rather than wall-clock time, so the
+    /// result holds exactly `duration * sample_rate` frames no matter how
+    /// the polling lines up with packets. The returned [`Format`] describes
+    /// the samples, for writing them out.
+    ///
+    /// Frames the device position skips, e.g. while a loopback endpoint has
+    /// nothing to play and delivers no packets, are filled with silence, so
+    /// the recording keeps matching wall-clock time. If the device goes
+    /// quiet for good, the rest is padded with silence once `duration` and
+    /// a few device periods have passed, instead of waiting forever.
+    ///
+    /// Returns early with what was recorded so far once the
+    /// [`stop_token`](Self::stop_token) is set. Fails with `E_INVALIDARG`
+    /// if `duration` is too long to be held in memory at all.
+    pub fn record_for(
+        &mut self,
+        duration: Duration,
+    ) -> Result<(Vec<f32>, Format), ReadSamplesError<Infallible>> {
+        let mut period = 0;
+        winapi_result(
+            unsafe { (*self.client).GetDevicePeriod(&mut period, null_mut()) },
+            "IAudioClient::GetDevicePeriod",
+        )?;
+        let period = reference_time_to_duration(period);
+        let deadline = duration.saturating_add(period.saturating_mul(4));
+
+        let sample_rate = self.wave_format_view().sample_rate();
+        let channels = self.channels as usize;
+        let target_len =
+            usize::try_from(duration_to_frames(sample_rate, duration))
+                .ok()
+                .and_then(|frames| frames.checked_mul(channels))
+                .ok_or_else(|| {
+                    WinError::new(E_INVALIDARG, "AudioCapture::record_for")
+                })?;
+
+        let stop_token = self.stop_token.clone();
+        let mut samples =
+            Vec::with_capacity(target_len.min(RECORD_RESERVE_LIMIT));
+        // device position right after the last packet, in frames
+        let mut next_position = None;
+        self.start()?;
+        let started = Instant::now();
+        let result = (|| -> Result<(), ReadSamplesError<Infallible>> {
+            while samples.len() < target_len && !stop_token.is_stopped() {
+                if started.elapsed() >= deadline {
+                    samples.resize(target_len, 0.0);
+                    break;
+                }
+                sleep(period / 2);
+                self.retry_invalidated(|this| {
+                    while samples.len() < target_len && !stop_token.is_stopped()
+                    {
+                        let read =
+                            this.read_packet_at(|data, info, position| {
+                                // a reinit starts counting from 0 again
+                                let gap = next_position.map_or(0, |next| {
+                                    position.saturating_sub(next)
+                                });
+                                next_position = Some(
+                                    position + (data.len() / channels) as u64,
+                                );
+                                let gap = usize::try_from(gap)
+                                    .unwrap_or(usize::MAX)
+                                    .saturating_mul(channels)
+                                    .min(target_len - samples.len());
+                                samples.resize(samples.len() + gap, 0.0);
+
+                                let start = samples.len();
+                                let missing = target_len - start;
+                                samples.extend_from_slice(
+                                    &data[..data.len().min(missing)],
+                                );
+                                if info.is_silent {
+                                    samples[start..].fill(0.0);
+                                }
+                            })?;
+                        if read.is_none() {
+                            break;
                         }
-                        _ => None,
                     }
+                    Ok(())
+                })?;
+            }
+            Ok(())
+        })();
+        // don't leave the stream running when reading failed
+        let stopped = self.stop();
+        result?;
+        stopped?;
+        let format = Format {
+            channels: self.channels,
+            sample_rate,
+            sample_format: SampleFormat::Float32,
+        };
+        Ok((samples, format))
+    }
+
+    /// Token that other threads can use to cancel
+    /// [`record_for`](Self::record_for) and
+    /// [`read_frames`](Self::read_frames). All tokens of a capture share one
+    /// flag, which stays set until [`StopToken::reset`].
+    pub fn stop_token(&self) -> StopToken {
+        self.stop_token.clone()
+    }
+
+    /// Flags of the last packet handed to a [`read_samples`] callback, also
+    /// when that callback returned an error.
+    ///
+    /// [`read_samples`]: Self::read_samples
+    pub fn last_info(&self) -> Option<Info> {
+        self.last_info
+    }
+
+    /// Counters accumulated by [`read_samples`](Self::read_samples) since
+    /// init or the last [`reset_stats`](Self::reset_stats).
+    pub fn stats(&self) -> CaptureStats {
+        self.stats
+    }
+
+    pub fn reset_stats(&mut self) {
+        self.stats = CaptureStats::default();
+    }
+
+    /// Reads samples from system's internal queue, running provided callback
+    /// for each "packet", then return.
+    ///
+    /// You will need to call this function in loop to keep reading new samples,
+    /// as it doesn't spawn background thread for you. It's done this way to
+    /// be more flexible for users.
+    pub fn read_samples<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<(), E>,
+    {
+        self.read_samples_until(|data, info| {
+            f(data, info).map(|()| ControlFlow::Continue(()))
+        })
+        .map(|_| ())
+    }
+
+    /// Like [`read_samples`](Self::read_samples), but the callback can
+    /// return [`ControlFlow::Break`] to stop reading after the current
+    /// packet, leaving the rest queued. Returns whether that happened.
+    pub fn read_samples_until<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<ControlFlow<()>, ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<ControlFlow<()>, E>,
+    {
+        self.retry_invalidated(|this| {
+            while let Some(r) = this.read_packet(&mut f)? {
+                if r.map_err(ReadSamplesError::E)?.is_break() {
+                    return Ok(ControlFlow::Break(()));
                 }
-                _ => None,
+            }
+            Ok(ControlFlow::Continue(()))
+        })
+    }
+
+    /// Runs `read`, and again after reinitializing the stream each time it
+    /// fails because the device got invalidated, as long as
+    /// [`auto_reinit`](CaptureConfig::auto_reinit) allows.
+    fn retry_invalidated<T, E>(
+        &mut self,
+        mut read: impl FnMut(&mut Self) -> Result<T, ReadSamplesError<E>>,
+    ) -> Result<T, ReadSamplesError<E>> {
+        let mut attempt = 0;
+        loop {
+            match read(self) {
+                Err(ReadSamplesError::WinError(e))
+                    if e.hresult == AUDCLNT_E_DEVICE_INVALIDATED =>
+                {
+                    auto_reinit(self, e, &mut attempt)?
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Like [`read_samples`](Self::read_samples), but silent packets are
+    /// reported as [`PacketKind::Silence`] with their length, instead of
+    /// passing on their buffer.
+    pub fn read_samples_marked<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(PacketKind, Info) -> Result<(), E>,
+    {
+        let channels = self.channels.max(1) as usize;
+        self.read_samples(|data, info| {
+            let kind = if info.is_silent {
+                PacketKind::Silence(data.len() / channels)
+            } else {
+                PacketKind::Audio(data)
             };
-            sample_rate = read_unaligned!(wave_format.nSamplesPerSec);
-            channels = read_unaligned!(wave_format.nChannels);
+            f(kind, info)
+        })
+    }
+
+    /// Like [`read_samples`](Self::read_samples), but hands out blocks of
+    /// exactly `frames` frames regardless of packet sizes, e.g. for encoders
+    /// working on fixed frame sizes. Samples that don't fill a whole block
+    /// are kept for the next call. Silent packets are passed on as zeros.
+    ///
+    /// A block may be made up of several packets, its [`Info`] then has
+    /// each flag set if any of those packets had it, so a glitch anywhere in
+    /// the block is reported.
+    ///
+    /// Stops between packets once the [`stop_token`](Self::stop_token) is
+    /// set.
+    pub fn read_frames<E, F>(
+        &mut self,
+        frames: usize,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<(), E>,
+    {
+        let block_len = frames * self.channels as usize;
+        if block_len == 0 {
+            return Ok(());
         }
-        let sample_format = sample_format.ok_or(UnknownFormat)?;
+        let mut pending = std::mem::take(&mut self.pending_frames);
+        let stop_token = self.stop_token.clone();
+        let result = self.read_samples_until(|data, info| {
+            pending
+                .push(data, info, block_len, &mut f)
+                .map(|()| stop_token.control_flow())
+        });
+        self.pending_frames = pending;
+        result.map(|_| ())
+    }
+
+    /// Reads every queued packet into one interleaved buffer, with silent
+    /// packets as zeros. Handy right before [`stop`](Self::stop) so the
+    /// tail of a recording isn't lost.
+    pub fn drain(&mut self) -> Result<Vec<f32>, WinError> {
+        let mut samples = Vec::new();
+        // samples read before the device got invalidated are kept
+        self.retry_invalidated(|this| {
+            while this
+                .read_packet(|data, info| {
+                    let start = samples.len();
+                    samples.extend_from_slice(data);
+                    if info.is_silent {
+                        samples[start..].fill(0.0);
+                    }
+                })?
+                .is_some()
+            {}
+            Ok(())
+        })
+        .map_err(ReadSamplesError::into_win_error)?;
+        Ok(samples)
+    }
+
+    /// Reads a single packet if one is queued, returning `Ok(None)` instead
+    /// of waiting otherwise. Handy for polling from an existing event loop.
+    pub fn try_read_packet(
+        &mut self,
+    ) -> Result<Option<(Vec<f32>, Info)>, WinError> {
+        self.retry_invalidated(|this| {
+            Ok(this.read_packet(|data, info| (data.to_vec(), info))?)
+        })
+        .map_err(ReadSamplesError::into_win_error)
+    }
+
+    /// Like [`read_samples`](Self::read_samples), but converts each packet
+    /// from the stream's sample format straight to `T`, which saves a
+    /// second pass when e.g. `i16` is wanted from an integer-format device.
+    pub fn read_samples_as<T, E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        T: Sample,
+        F: FnMut(&[T], Info) -> Result<(), E>,
+    {
+        self.read_samples_bytes(
+            "AudioCapture::read_samples_as",
+            |bytes, sample_format, info| {
+                f(&T::from_wasapi_bytes(sample_format, bytes), info)
+            },
+        )
+    }
+
+    /// Like [`read_samples`](Self::read_samples), but hands out each packet
+    /// both as the untouched bytes in the stream's format and decoded to
+    /// `f32`, e.g. for writing the original bit depth while metering.
+    ///
+    /// [`pre_gain`](CaptureConfig::pre_gain) and
+    /// [`clamp`](CaptureConfig::clamp) only apply to the decoded samples,
+    /// the bytes are passed on as the device delivered them.
+    pub fn read_samples_dual<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[u8], &[f32], Info) -> Result<(), E>,
+    {
+        let (pre_gain, clamp) = self.gain();
+        self.read_samples_bytes(
+            "AudioCapture::read_samples_dual",
+            |bytes, sample_format, info| {
+                let mut samples = f32::from_wasapi_bytes(sample_format, bytes);
+                apply_gain(&mut samples, pre_gain, clamp);
+                f(bytes, &samples, info)
+            },
+        )
+    }
+
+    /// Runs `f` on the raw bytes of each queued packet, along with the
+    /// sample format they're in.
+    fn read_samples_bytes<E, F>(
+        &mut self,
+        context: &'static str,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[u8], SampleFormat, Info) -> Result<(), E>,
+    {
+        self.retry_invalidated(|this| {
+            // a reinit may have picked up a different mix format
+            let sample_format = this
+                .format()
+                .map_err(|_| {
+                    WinError::new(AUDCLNT_E_UNSUPPORTED_FORMAT, context)
+                })?
+                .sample_format;
+            let block_align = this.wave_format_view().block_align();
+            while let Some(r) =
+                this.read_packet_raw(|buffer, frames, info, _| {
+                    let bytes = unsafe {
+                        std::slice::from_raw_parts(
+                            buffer,
+                            frames as usize * block_align as usize,
+                        )
+                    };
+                    f(bytes, sample_format, info)
+                })?
+            {
+                r.map_err(ReadSamplesError::E)?;
+            }
+            Ok(())
+        })
+    }
+
+    /// Runs `f` on the next packet, if there is one, and releases it.
+    fn read_packet<R>(
+        &mut self,
+        f: impl FnOnce(&[f32], Info) -> R,
+    ) -> Result<Option<R>, WinError> {
+        self.read_packet_at(|data, info, _| f(data, info))
+    }
+
+    /// Like [`read_packet`](Self::read_packet), but also passes the device
+    /// position of the packet's first frame.
+    fn read_packet_at<R>(
+        &mut self,
+        f: impl FnOnce(&[f32], Info, u64) -> R,
+    ) -> Result<Option<R>, WinError> {
+        let channels = self.channels as usize;
+        let (pre_gain, clamp) = self.gain();
+        let mut scratch = std::mem::take(&mut self.scratch);
+        let r = self.read_packet_raw(|buffer, frames, info, position| {
+            let data = unsafe {
+                std::slice::from_raw_parts(
+                    buffer as *const f32,
+                    frames as usize * channels,
+                )
+            };
+            if pre_gain == 1.0 && !clamp {
+                return f(data, info, position);
+            }
+            scratch.clear();
+            scratch.extend_from_slice(data);
+            apply_gain(&mut scratch, pre_gain, clamp);
+            f(&scratch, info, position)
+        });
+        self.scratch = scratch;
+        r
+    }
+
+    /// `pre_gain` and `clamp` from the config, process loopback has neither.
+    fn gain(&self) -> (f32, bool) {
+        match &self.origin {
+            Origin::Config(config) => (config.pre_gain, config.clamp),
+            Origin::ProcessLoopback { .. } => (1.0, false),
+        }
+    }
+
+    /// Runs `f` on the buffer, frame count and device position of the next
+    /// packet, if there is one, and releases it.
+    fn read_packet_raw<R>(
+        &mut self,
+        f: impl FnOnce(*const u8, u32, Info, u64) -> R,
+    ) -> Result<Option<R>, WinError> {
+        if let Some(keepalive) = &self.keepalive {
+            keepalive.fill()?;
+        }
+
+        let mut packet_length = 0;
+        winapi_result(
+            unsafe {
+                (*self.capture_client).GetNextPacketSize(&mut packet_length)
+            },
+            "IAudioCaptureClient::GetNextPacketSize",
+        )?;
+        if packet_length == 0 {
+            return Ok(None);
+        }
+
+        let mut buffer: *mut u8 = null_mut();
+        let mut buffer_size = 0;
+        let mut flags = 0;
+        let mut position = 0;
+        winapi_result(
+            unsafe {
+                (*self.capture_client).GetBuffer(
+                    &mut buffer,
+                    &mut buffer_size,
+                    &mut flags,
+                    &mut position,
+                    null_mut(),
+                )
+            },
+            "IAudioCaptureClient::GetBuffer",
+        )?;
+
+        let is_silent = (flags & AUDCLNT_BUFFERFLAGS_SILENT) != 0;
+        let data_discontinuity =
+            (flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY) != 0;
+        let timestamp_error =
+            (flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR) != 0;
+
+        if std::mem::take(&mut self.skip_discontinuity) && data_discontinuity {
+            winapi_result(
+                unsafe { (*self.capture_client).ReleaseBuffer(buffer_size) },
+                "IAudioCaptureClient::ReleaseBuffer",
+            )?;
+            return self.read_packet_raw(f);
+        }
+
+        let info = Info {
+            is_silent,
+            data_discontinuity,
+            timestamp_error,
+        };
+
+        self.last_info = Some(info);
+        self.stats.record(buffer_size, info);
+        let r = f(buffer, buffer_size, info, position);
+
+        winapi_result(
+            unsafe { (*self.capture_client).ReleaseBuffer(buffer_size) },
+            "IAudioCaptureClient::ReleaseBuffer",
+        )?;
+
+        Ok(Some(r))
+    }
+}
+
+impl Format {
+    /// Recognizes the format described by `wave_format`, e.g. one parsed from
+    /// a WAV header. Plain PCM and float formats are understood, as well as
+    /// their `WAVE_FORMAT_EXTENSIBLE` variants.
+    ///
+    /// Plain formats are accepted with any channel count, as some drivers
+    /// report multichannel mix formats without a channel mask. Channels are
+    /// then assumed to be in the usual `WAVE_FORMAT_EXTENSIBLE` speaker
+    /// order (front left, front right, center, LFE, back left, back right,
+    /// ...), which is also what [`dsp`](crate::dsp) expects.
+    ///
+    /// # Safety
+    /// `wave_format` must point to a valid `WAVEFORMATEX`, which is read as a
+    /// `WAVEFORMATEXTENSIBLE` if its `wFormatTag` and `cbSize` say so.
+    pub unsafe fn from_wave_format(
+        wave_format: *const WAVEFORMATEX,
+    ) -> Result<Format, UnknownFormat> {
+        Self::from_wave_format_view(WaveFormatView::new(wave_format))
+    }
+
+    /// Safe counterpart of [`from_wave_format`](Self::from_wave_format).
+    pub fn from_wave_format_view(
+        view: WaveFormatView,
+    ) -> Result<Format, UnknownFormat> {
+        let format_tag = view.format_tag();
+        let sample_bitsize = view.bits_per_sample();
+        let sub_format = view.sub_format();
+        // plain tags don't depend on the channel count, so e.g. 6-channel
+        // WAVE_FORMAT_IEEE_FLOAT without a mask is recognized too
+        let sample_format = match (format_tag, sample_bitsize, sub_format) {
+            (WAVE_FORMAT_PCM, 8, _) => Some(SampleFormat::Int8),
+            (WAVE_FORMAT_PCM, 16, _) => Some(SampleFormat::Int16),
+            (WAVE_FORMAT_IEEE_FLOAT, 32, _) => Some(SampleFormat::Float32),
+            (WAVE_FORMAT_EXTENSIBLE, _, Some(sub_format)) => {
+                match (sub_format.known_subtype(), sample_bitsize) {
+                    (Some(KnownSubtype::Pcm), 8) => Some(SampleFormat::Int8),
+                    (Some(KnownSubtype::Pcm), 16) => Some(SampleFormat::Int16),
+                    (Some(KnownSubtype::IeeeFloat), 32) => {
+                        Some(SampleFormat::Float32)
+                    }
+                    _ => None,
+                }
+            }
+            _ => None,
+        };
+        let sample_rate = view.sample_rate();
+        let channels = view.channels();
+        let error = UnknownFormat {
+            format_tag,
+            bits_per_sample: sample_bitsize,
+            sub_format,
+            channels,
+            sample_rate,
+        };
+        // a bogus driver format would otherwise lead to empty packets and
+        // divisions by zero further down the line
+        if channels == 0 || sample_rate == 0 {
+            return Err(error);
+        }
+        let sample_format = sample_format.ok_or(error)?;
+
+        Ok(Format {
+            channels,
+            sample_rate,
+            sample_format,
+        })
+    }
+}
+
+/// Common interface of [`AudioCapture`] and, with the `mock` feature,
+/// `MockCapture`, so code consuming captured
+/// audio can be tested without an audio device.
+pub trait Capture {
+    fn format(&self) -> Result<Format, UnknownFormat>;
+    fn start(&mut self) -> Result<(), WinError>;
+    fn stop(&mut self) -> Result<(), WinError>;
+    fn read_samples<E, F>(&mut self, f: F) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<(), E>;
+}
 
-        Ok(Format {
-            channels,
-            sample_rate,
-            sample_format,
-        })
+impl Capture for AudioCapture {
+    fn format(&self) -> Result<Format, UnknownFormat> {
+        AudioCapture::format(self)
     }
 
-    pub fn start(&mut self) -> Result<(), WinError> {
-        winapi_result(unsafe { (*self.client).Start() })
+    fn start(&mut self) -> Result<(), WinError> {
+        AudioCapture::start(self)
     }
 
-    pub fn stop(&mut self) -> Result<(), WinError> {
-        winapi_result(unsafe { (*self.client).Stop() })
+    fn stop(&mut self) -> Result<(), WinError> {
+        AudioCapture::stop(self)
     }
 
-    /// Reads samples from system's internal queue, running provided callback
-    /// for each "packet", then return.
-    /// 
-    /// You will need to call this function in loop to keep reading new samples,
-    /// as it doesn't spawn background thread for you. It's done this way to
-    /// be more flexible for users.
+    fn read_samples<E, F>(&mut self, f: F) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<(), E>,
+    {
+        AudioCapture::read_samples(self, f)
+    }
+}
+
+/// Running stream, returned by [`AudioCapture::activate`]. The stream is
+/// stopped when this is dropped, including on early returns and panics.
+pub struct ActiveCapture<'a> {
+    capture: &'a mut AudioCapture,
+}
+
+impl ActiveCapture<'_> {
+    /// See [`AudioCapture::read_samples`].
     pub fn read_samples<E, F>(
         &mut self,
-        mut f: F,
+        f: F,
     ) -> Result<(), ReadSamplesError<E>>
     where
         F: FnMut(&[f32], Info) -> Result<(), E>,
     {
-        let mut packet_length = 0;
-        winapi_result(unsafe {
-            (*self.capture_client).GetNextPacketSize(&mut packet_length)
-        })?;
-
-        while packet_length > 0 {
-            let mut buffer: *mut u8 = null_mut();
-            let mut buffer_size = 0;
-            let mut flags = 0;
-            winapi_result(unsafe {
-                (*self.capture_client).GetBuffer(
-                    &mut buffer,
-                    &mut buffer_size,
-                    &mut flags,
+        self.capture.read_samples(f)
+    }
+}
+
+impl Deref for ActiveCapture<'_> {
+    type Target = AudioCapture;
+
+    fn deref(&self) -> &AudioCapture {
+        self.capture
+    }
+}
+
+impl Drop for ActiveCapture<'_> {
+    fn drop(&mut self) {
+        if let Err(e) = self.capture.stop() {
+            warn!("failed to stop capture: {}", e);
+        }
+    }
+}
+
+/// Render stream on the loopback endpoint that only ever plays silence.
+struct RenderKeepalive {
+    buffer_frame_size: u32,
+    client: *mut IAudioClient,
+    render_client: *mut IAudioRenderClient,
+}
+
+impl RenderKeepalive {
+    fn init(device: *mut IMMDevice, dur: i64) -> Result<Self, WinError> {
+        let mut client: *mut IAudioClient = null_mut();
+        winapi_result(
+            unsafe {
+                (&*device).Activate(
+                    &IAudioClient::uuidof(),
+                    CLSCTX_ALL,
                     null_mut(),
+                    &mut client as *mut _ as _,
+                )
+            },
+            "IMMDevice::Activate",
+        )?;
+        // releases the client again if any of the following steps fail
+        let mut keepalive = Self {
+            buffer_frame_size: 0,
+            client,
+            render_client: null_mut(),
+        };
+
+        let mut wave_format: *mut WAVEFORMATEX = null_mut();
+        winapi_result(
+            unsafe { (&*client).GetMixFormat(&mut wave_format) },
+            "IAudioClient::GetMixFormat",
+        )?;
+        let result = winapi_result(
+            unsafe {
+                (&*client).Initialize(
+                    AUDCLNT_SHAREMODE_SHARED,
+                    0,
+                    dur,
+                    0,
+                    wave_format,
                     null_mut(),
                 )
-            })?;
+            },
+            "IAudioClient::Initialize",
+        );
+        unsafe { CoTaskMemFree(wave_format as _) };
+        result?;
 
-            let is_silent = (flags & AUDCLNT_BUFFERFLAGS_SILENT) != 0;
-            let data_discontinuity =
-                (flags & AUDCLNT_BUFFERFLAGS_DATA_DISCONTINUITY) != 0;
-            let timestamp_error =
-                (flags & AUDCLNT_BUFFERFLAGS_TIMESTAMP_ERROR) != 0;
+        winapi_result(
+            unsafe {
+                (&*client).GetBufferSize(&mut keepalive.buffer_frame_size)
+            },
+            "IAudioClient::GetBufferSize",
+        )?;
 
-            let data = unsafe {
-                std::slice::from_raw_parts(
-                    buffer as *mut f32,
-                    buffer_size as usize * self.channels as usize,
+        winapi_result(
+            unsafe {
+                (&*client).GetService(
+                    &IAudioRenderClient::uuidof(),
+                    &mut keepalive.render_client as *mut _ as _,
                 )
-            };
+            },
+            "IAudioClient::GetService",
+        )?;
 
-            let info = Info {
-                is_silent,
-                data_discontinuity,
-                timestamp_error,
-            };
+        keepalive.fill()?;
+        Ok(keepalive)
+    }
 
-            let r = f(data, info).map_err(|e| ReadSamplesError::E(e));
+    /// Tops up the render buffer with silence.
+    fn fill(&self) -> Result<(), WinError> {
+        let mut padding = 0;
+        winapi_result(
+            unsafe { (*self.client).GetCurrentPadding(&mut padding) },
+            "IAudioClient::GetCurrentPadding",
+        )?;
+        let frames = self.buffer_frame_size - padding;
+        if frames == 0 {
+            return Ok(());
+        }
+        let mut buffer = null_mut();
+        winapi_result(
+            unsafe { (*self.render_client).GetBuffer(frames, &mut buffer) },
+            "IAudioRenderClient::GetBuffer",
+        )?;
+        // the silent flag makes WASAPI ignore the buffer contents
+        winapi_result(
+            unsafe {
+                (*self.render_client)
+                    .ReleaseBuffer(frames, AUDCLNT_BUFFERFLAGS_SILENT)
+            },
+            "IAudioRenderClient::ReleaseBuffer",
+        )
+    }
 
-            winapi_result(unsafe {
-                (*self.capture_client).ReleaseBuffer(buffer_size)
-            })?;
+    fn start(&self) -> Result<(), WinError> {
+        winapi_result(unsafe { (*self.client).Start() }, "IAudioClient::Start")
+    }
 
-            r?;
+    fn stop(&self) -> Result<(), WinError> {
+        winapi_result(unsafe { (*self.client).Stop() }, "IAudioClient::Stop")
+    }
+}
 
-            winapi_result(unsafe {
-                (*self.capture_client).GetNextPacketSize(&mut packet_length)
-            })?;
+impl Drop for RenderKeepalive {
+    fn drop(&mut self) {
+        unsafe {
+            let _ = (*self.client).Stop();
+            // null if init failed half way
+            if !self.render_client.is_null() {
+                (*self.render_client).Release();
+            }
+            (*self.client).Release();
         }
-        Ok(())
+    }
+}
+
+/// Probes which common formats endpoint `device_id` accepts in exclusive
+/// mode. Shared mode, the only mode streams are opened in, always runs at
+/// the mix format, so this is mostly useful for showing valid exclusive-mode
+/// options.
+pub fn list_supported_formats(
+    device_id: &str,
+) -> Result<Vec<Format>, WinError> {
+    const SAMPLE_RATES: [u32; 6] = [44100, 48000, 88200, 96000, 176400, 192000];
+    const CHANNELS: [u16; 5] = [1, 2, 4, 6, 8];
+    const SAMPLE_FORMATS: [SampleFormat; 3] = [
+        SampleFormat::Int8,
+        SampleFormat::Int16,
+        SampleFormat::Float32,
+    ];
+
+    let should_run_couninitilize = co_initialize(ComThreading::default());
+    let result = (|| {
+        let mut enumerator: *mut IMMDeviceEnumerator = null_mut();
+        winapi_result(
+            unsafe {
+                CoCreateInstance(
+                    &MMDeviceEnumerator::uuidof(),
+                    null_mut(),
+                    CLSCTX_ALL,
+                    &IMMDeviceEnumerator::uuidof(),
+                    &mut enumerator as *mut _ as _,
+                )
+            },
+            "CoCreateInstance",
+        )?;
+        let id: Vec<u16> =
+            OsStr::new(device_id).encode_wide().chain(once(0)).collect();
+        let mut device: *mut IMMDevice = null_mut();
+        let result = winapi_result(
+            unsafe { (*enumerator).GetDevice(id.as_ptr(), &mut device) },
+            "IMMDeviceEnumerator::GetDevice",
+        );
+        unsafe { (*enumerator).Release() };
+        result?;
+
+        let mut client: *mut IAudioClient = null_mut();
+        let result = winapi_result(
+            unsafe {
+                (*device).Activate(
+                    &IAudioClient::uuidof(),
+                    CLSCTX_ALL,
+                    null_mut(),
+                    &mut client as *mut _ as _,
+                )
+            },
+            "IMMDevice::Activate",
+        );
+        unsafe { (*device).Release() };
+        result?;
+
+        let mut supported = Vec::new();
+        for sample_rate in SAMPLE_RATES {
+            for channels in CHANNELS {
+                for sample_format in SAMPLE_FORMATS {
+                    let format = Format {
+                        channels,
+                        sample_rate,
+                        sample_format,
+                    };
+                    let wave_format = alloc_wave_format(&format);
+                    // exclusive mode never suggests a closest match, anything
+                    // but S_OK means unsupported
+                    let hresult = unsafe {
+                        (*client).IsFormatSupported(
+                            AUDCLNT_SHAREMODE_EXCLUSIVE,
+                            wave_format,
+                            null_mut(),
+                        )
+                    };
+                    unsafe { CoTaskMemFree(wave_format as _) };
+                    if hresult == S_OK {
+                        supported.push(format);
+                    }
+                }
+            }
+        }
+        unsafe { (*client).Release() };
+        Ok(supported)
+    })();
+    if should_run_couninitilize {
+        unsafe { CoUninitialize() };
+    }
+    result
+}
+
+/// Scales `samples` by `pre_gain`, then clamps them to full scale if `clamp`
+/// is set.
+fn apply_gain(samples: &mut [f32], pre_gain: f32, clamp: bool) {
+    if pre_gain == 1.0 && !clamp {
+        return;
+    }
+    for s in samples {
+        *s *= pre_gain;
+        if clamp {
+            *s = s.clamp(-1.0, 1.0);
+        }
+    }
+}
+
+/// Allocates a `WAVEFORMATEXTENSIBLE` describing `format` with
+/// `CoTaskMemAlloc`, so it can be freed the same way as the mix format.
+fn alloc_wave_format(format: &Format) -> *mut WAVEFORMATEX {
+    let bits_per_sample = format.sample_format.bits_per_sample();
+    let block_align = format.channels * bits_per_sample / 8;
+    let channel_mask = default_channel_mask(format.channels);
+    let sub_format = match format.sample_format {
+        SampleFormat::Int8 | SampleFormat::Int16 => KSDATAFORMAT_SUBTYPE_PCM,
+        SampleFormat::Float32 => KSDATAFORMAT_SUBTYPE_IEEE_FLOAT,
+    };
+    let extensible = WAVEFORMATEXTENSIBLE {
+        Format: WAVEFORMATEX {
+            wFormatTag: WAVE_FORMAT_EXTENSIBLE,
+            nChannels: format.channels,
+            nSamplesPerSec: format.sample_rate,
+            nAvgBytesPerSec: format.sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: bits_per_sample,
+            cbSize: (size_of::<WAVEFORMATEXTENSIBLE>()
+                - size_of::<WAVEFORMATEX>()) as u16,
+        },
+        Samples: bits_per_sample,
+        dwChannelMask: channel_mask,
+        SubFormat: sub_format,
+    };
+    unsafe {
+        let ptr: *mut WAVEFORMATEXTENSIBLE =
+            CoTaskMemAlloc(size_of::<WAVEFORMATEXTENSIBLE>()) as _;
+        assert!(!ptr.is_null(), "out of memory");
+        ptr.write_unaligned(extensible);
+        ptr as _
     }
 }
 
@@ -286,6 +1849,15 @@ impl<E: std::error::Error + 'static> std::error::Error for ReadSamplesError<E> {
     }
 }
 
+impl ReadSamplesError<Infallible> {
+    pub(crate) fn into_win_error(self) -> WinError {
+        match self {
+            Self::E(never) => match never {},
+            Self::WinError(e) => e,
+        }
+    }
+}
+
 impl<E> From<WinError> for ReadSamplesError<E> {
     fn from(e: WinError) -> Self {
         Self::WinError(e)
@@ -297,12 +1869,26 @@ unsafe impl Sync for AudioCapture {}
 
 impl Drop for AudioCapture {
     fn drop(&mut self) {
+        // nothing useful to do with the error this late
+        if let Err(e) = self.stop() {
+            warn!("failed to stop capture on drop: {}", e);
+        }
+        // holds a client activated from `device`
+        self.keepalive = None;
         unsafe {
             CoTaskMemFree(self.wave_format as _);
             (*self.capture_client).Release();
             (*self.client).Release();
-            (*self.device).Release();
-            (*self.enumerator).Release();
+            // both are null for process loopback
+            if !self.device.is_null() {
+                (*self.device).Release();
+            }
+            if !self.enumerator.is_null() {
+                (*self.enumerator).Release();
+            }
+            if !self.event.is_null() {
+                CloseHandle(self.event);
+            }
 
             if self.should_run_couninitalize_on_drop {
                 CoUninitialize();
@@ -311,20 +1897,679 @@ impl Drop for AudioCapture {
     }
 }
 
+/// Samples and flags of packets that didn't fill a whole
+/// [`read_frames`](AudioCapture::read_frames) block yet.
+#[derive(Debug, Default)]
+struct PendingFrames {
+    samples: Vec<f32>,
+    info: Info,
+}
+
+impl PendingFrames {
+    /// Appends a packet, zeroed if it's silent, and hands every block of
+    /// `block_len` samples completed by it to `f`.
+    fn push<E>(
+        &mut self,
+        data: &[f32],
+        info: Info,
+        block_len: usize,
+        mut f: impl FnMut(&[f32], Info) -> Result<(), E>,
+    ) -> Result<(), E> {
+        let start = self.samples.len();
+        self.samples.extend_from_slice(data);
+        if info.is_silent {
+            self.samples[start..].fill(0.0);
+        }
+        // only the first block can contain samples of earlier packets
+        let mut block_info = self.info.merged(info);
+        // drop blocks already handed out even if `f` fails on a later one,
+        // so they aren't repeated by the next call
+        let mut blocks = 0;
+        let result =
+            self.samples.chunks_exact(block_len).try_for_each(|block| {
+                blocks += 1;
+                let result = f(block, block_info);
+                block_info = info;
+                result
+            });
+        self.samples.drain(..blocks * block_len);
+        self.info = match (blocks, self.samples.is_empty()) {
+            (0, _) => block_info,
+            (_, true) => Info::default(),
+            (_, false) => info,
+        };
+        result
+    }
+}
+
+/// Opening and swapping in streams for [`AudioCapture::reinit`], the part
+/// of reinitializing that needs WASAPI.
+trait Reopen: Sized {
+    /// Opens a new stream with the options in `origin`.
+    fn open(origin: Origin) -> Result<Self, WinError>;
+
+    /// Options the stream was opened with
+    fn origin(&self) -> &Origin;
+
+    fn set_origin(&mut self, origin: Origin);
+
+    /// Replaces `self` by `new`, restarting it if `self` was running.
+    fn replace(&mut self, new: Self) -> Result<(), WinError>;
+}
+
+impl Reopen for AudioCapture {
+    fn open(origin: Origin) -> Result<Self, WinError> {
+        match origin {
+            Origin::Config(config) => Self::init_with_config(config),
+            Origin::ProcessLoopback {
+                pid,
+                buffer_duration,
+            } => Self::init_process_loopback(pid, buffer_duration),
+        }
+    }
+
+    fn origin(&self) -> &Origin {
+        &self.origin
+    }
+
+    fn set_origin(&mut self, origin: Origin) {
+        self.origin = origin;
+    }
+
+    fn replace(&mut self, mut new: Self) -> Result<(), WinError> {
+        // COM is still initialized by `self` at this point, hand over the
+        // responsibility of uninitializing it
+        new.should_run_couninitalize_on_drop |=
+            std::mem::take(&mut self.should_run_couninitalize_on_drop);
+        // keep tokens handed out so far working
+        new.stop_token = self.stop_token.clone();
+        let was_running = self.running;
+        *self = new;
+        if was_running {
+            self.start()?;
+        }
+        Ok(())
+    }
+}
+
+fn reinit_staged<S: Reopen>(stream: &mut S) -> Result<(), ReinitError> {
+    let new = S::open(stream.origin().clone()).map_err(ReinitError::Init)?;
+    stream.replace(new).map_err(ReinitError::Start)
+}
+
+fn set_buffer_duration<S: Reopen>(
+    stream: &mut S,
+    duration: Duration,
+) -> Result<(), WinError> {
+    let previous = stream.origin().clone();
+    stream.set_origin(previous.with_buffer_duration(duration));
+    match reinit_staged(stream) {
+        Ok(()) => Ok(()),
+        Err(ReinitError::Init(e)) => {
+            // the old stream is still open, keep describing it
+            stream.set_origin(previous);
+            Err(e)
+        }
+        // the new stream replaced the old one and is just not running
+        Err(ReinitError::Start(e)) => Err(e),
+    }
+}
+
+/// Tries to recover `stream` from `error` by reinitializing, with
+/// exponential backoff, until it works or the configured attempts run out.
+fn auto_reinit<S: Reopen>(
+    stream: &mut S,
+    mut error: WinError,
+    attempt: &mut u32,
+) -> Result<(), WinError> {
+    let max_attempts = match stream.origin() {
+        Origin::Config(config) => config.auto_reinit,
+        Origin::ProcessLoopback { .. } => 0,
+    };
+    while *attempt < max_attempts {
+        sleep(
+            AUTO_REINIT_BACKOFF
+                .saturating_mul(2u32.saturating_pow(*attempt))
+                .min(AUTO_REINIT_MAX_BACKOFF),
+        );
+        *attempt += 1;
+        match reinit_staged(stream) {
+            Ok(()) => return Ok(()),
+            Err(ReinitError::Init(e) | ReinitError::Start(e)) => error = e,
+        }
+    }
+    Err(error)
+}
+
+/// Which step of [`AudioCapture::reinit`] failed
+#[derive(Debug)]
+enum ReinitError {
+    /// Opening the new stream, the old one is left as it was
+    Init(WinError),
+    /// Restarting the new stream, which already replaced the old one
+    Start(WinError),
+}
+
+/// What `AudioCapture::init_with_config` or `init_process_loopback` has
+/// acquired so far, released again if a later step fails.
+struct PartialInit {
+    enumerator: *mut IMMDeviceEnumerator,
+    device: *mut IMMDevice,
+    client: *mut IAudioClient,
+    wave_format: *mut WAVEFORMATEX,
+    capture_client: *mut IAudioCaptureClient,
+    event: HANDLE,
+    should_run_couninitialize: bool,
+}
+
+impl PartialInit {
+    fn new(should_run_couninitialize: bool) -> Self {
+        Self {
+            enumerator: null_mut(),
+            device: null_mut(),
+            client: null_mut(),
+            wave_format: null_mut(),
+            capture_client: null_mut(),
+            event: null_mut(),
+            should_run_couninitialize,
+        }
+    }
+}
+
+impl Drop for PartialInit {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.capture_client.is_null() {
+                (*self.capture_client).Release();
+            }
+            if !self.client.is_null() {
+                (*self.client).Release();
+            }
+            if !self.device.is_null() {
+                (*self.device).Release();
+            }
+            if !self.enumerator.is_null() {
+                (*self.enumerator).Release();
+            }
+            CoTaskMemFree(self.wave_format as _);
+            if !self.event.is_null() {
+                CloseHandle(self.event);
+            }
+            if self.should_run_couninitialize {
+                CoUninitialize();
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CaptureStats {
+    pub packets: u64,
+    pub frames: u64,
+    pub discontinuities: u64,
+    pub silent_packets: u64,
+    pub timestamp_errors: u64,
+}
+
+impl CaptureStats {
+    fn record(&mut self, frames: u32, info: Info) {
+        self.packets += 1;
+        self.frames += frames as u64;
+        self.discontinuities += info.data_discontinuity as u64;
+        self.silent_packets += info.is_silent as u64;
+        self.timestamp_errors += info.timestamp_error as u64;
+    }
+}
+
 #[allow(unused)]
+#[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
 pub struct Info {
     pub is_silent: bool,
     pub data_discontinuity: bool,
     pub timestamp_error: bool,
 }
 
-#[derive(Debug)]
-pub struct UnknownFormat;
+impl Info {
+    /// Flags of a span made up of both `self` and `other`
+    fn merged(self, other: Info) -> Info {
+        Info {
+            is_silent: self.is_silent || other.is_silent,
+            data_discontinuity: self.data_discontinuity
+                || other.data_discontinuity,
+            timestamp_error: self.timestamp_error || other.timestamp_error,
+        }
+    }
+}
+
+/// Cross-thread cancellation flag, see [`AudioCapture::stop_token`]
+#[derive(Debug, Clone, Default)]
+pub struct StopToken(Arc<AtomicBool>);
+
+impl StopToken {
+    pub fn stop(&self) {
+        self.0.store(true, Ordering::Relaxed);
+    }
+
+    pub fn reset(&self) {
+        self.0.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_stopped(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    fn control_flow(&self) -> ControlFlow<()> {
+        if self.is_stopped() {
+            ControlFlow::Break(())
+        } else {
+            ControlFlow::Continue(())
+        }
+    }
+}
+
+/// Packet contents as handed out by
+/// [`read_samples_marked`](AudioCapture::read_samples_marked)
+#[derive(Debug, Clone, Copy)]
+pub enum PacketKind<'a> {
+    /// Interleaved samples
+    Audio(&'a [f32]),
+    /// Frame count of a packet flagged as silent, whose buffer contents are
+    /// meaningless
+    Silence(usize),
+}
+
+#[derive(Debug, Clone)]
+pub struct UnknownFormat {
+    pub format_tag: u16,
+    pub bits_per_sample: u16,
+    /// Only present for `WAVE_FORMAT_EXTENSIBLE` formats
+    pub sub_format: Option<Guid>,
+    pub channels: u16,
+    pub sample_rate: u32,
+}
 
 impl fmt::Display for UnknownFormat {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::Debug::fmt(self, f)
+        if self.channels == 0 {
+            return write!(f, "invalid format (zero channels)");
+        }
+        if self.sample_rate == 0 {
+            return write!(f, "invalid format (zero sample rate)");
+        }
+        write!(
+            f,
+            "unknown format (tag 0x{:04X}, {} bits per sample",
+            self.format_tag, self.bits_per_sample
+        )?;
+        if let Some(sub_format) = self.sub_format {
+            match sub_format.known_subtype() {
+                Some(known) => {
+                    write!(f, ", subformat {:?} {}", known, sub_format)?
+                }
+                None => write!(f, ", subformat {}", sub_format)?,
+            }
+        }
+        write!(f, ")")
     }
 }
 
 impl std::error::Error for UnknownFormat {}
+
+#[cfg(test)]
+mod tests {
+    use std::cell::{Cell, RefCell};
+
+    use super::*;
+
+    fn target_config() -> CaptureConfig {
+        AudioCaptureBuilder::new()
+            .loopback(true)
+            .buffer_frames(480)
+            .target_format(Format {
+                channels: 2,
+                sample_rate: 44100,
+                sample_format: SampleFormat::Float32,
+            })
+            .pre_gain(0.5)
+            .clamp(true)
+            .config
+    }
+
+    #[test]
+    fn buffer_duration_change_keeps_config() {
+        let config = target_config();
+        let origin = Origin::Config(config.clone())
+            .with_buffer_duration(Duration::from_millis(20));
+        let Origin::Config(changed) = origin else {
+            panic!("origin changed kind");
+        };
+        assert_eq!(changed.buffer_duration, Duration::from_millis(20));
+        // an explicit frame count would override the new duration
+        assert_eq!(changed.buffer_frames, None);
+        assert_eq!(changed.loopback, config.loopback);
+        assert_eq!(changed.target_format, config.target_format);
+        assert_eq!(changed.stream_flags, config.stream_flags);
+        assert_eq!(changed.pre_gain, config.pre_gain);
+        assert_eq!(changed.clamp, config.clamp);
+    }
+
+    #[test]
+    fn buffer_duration_change_keeps_process() {
+        let origin = Origin::ProcessLoopback {
+            pid: 1234,
+            buffer_duration: Duration::ZERO,
+        }
+        .with_buffer_duration(Duration::from_millis(20));
+        assert!(matches!(
+            origin,
+            Origin::ProcessLoopback {
+                pid: 1234,
+                buffer_duration,
+            } if buffer_duration == Duration::from_millis(20)
+        ));
+    }
+
+    const GLITCH: Info = Info {
+        is_silent: false,
+        data_discontinuity: true,
+        timestamp_error: false,
+    };
+    const SILENT: Info = Info {
+        is_silent: true,
+        data_discontinuity: false,
+        timestamp_error: false,
+    };
+
+    /// Runs a schedule of `(frame count, flags)` packets of mono samples
+    /// counting up from 1 through [`PendingFrames`], collecting the blocks.
+    fn blocks(
+        packets: &[(usize, Info)],
+        block_frames: usize,
+    ) -> (Vec<(Vec<f32>, Info)>, PendingFrames) {
+        let mut pending = PendingFrames::default();
+        let mut blocks = Vec::new();
+        let mut next = 1.0;
+        for &(frames, info) in packets {
+            let data: Vec<f32> = (0..frames).map(|i| next + i as f32).collect();
+            next += frames as f32;
+            pending
+                .push(&data, info, block_frames, |block, info| {
+                    blocks.push((block.to_vec(), info));
+                    Ok::<_, Infallible>(())
+                })
+                .unwrap();
+        }
+        (blocks, pending)
+    }
+
+    #[test]
+    fn read_frames_splits_misaligned_packets() {
+        let clean = Info::default();
+        let (blocks, pending) =
+            blocks(&[(3, clean), (3, clean), (5, clean)], 4);
+        let samples: Vec<_> = blocks.iter().map(|(b, _)| b.clone()).collect();
+        assert_eq!(
+            samples,
+            [vec![1.0, 2.0, 3.0, 4.0], vec![5.0, 6.0, 7.0, 8.0],]
+        );
+        assert_eq!(pending.samples, [9.0, 10.0, 11.0]);
+    }
+
+    #[test]
+    fn read_frames_merges_flags() {
+        let clean = Info::default();
+        let (blocks, pending) = blocks(
+            &[(2, clean), (3, GLITCH), (4, clean), (1, SILENT), (2, clean)],
+            4,
+        );
+        let infos: Vec<_> = blocks.iter().map(|&(_, info)| info).collect();
+        // the glitch lands in the first two blocks, the silent packet in
+        // the third, which only completes with the last packet
+        assert_eq!(infos, [GLITCH, GLITCH, SILENT]);
+        assert_eq!(blocks[2].0, [9.0, 0.0, 11.0, 12.0]);
+        assert!(pending.samples.is_empty());
+        assert_eq!(pending.info, Info::default());
+    }
+
+    #[test]
+    fn read_frames_keeps_flags_of_pending_samples() {
+        let (blocks, pending) = blocks(&[(6, GLITCH)], 4);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(pending.samples, [5.0, 6.0]);
+        assert_eq!(pending.info, GLITCH);
+    }
+
+    #[test]
+    fn read_frames_drops_handed_out_blocks_on_error() {
+        let mut pending = PendingFrames::default();
+        let mut calls = 0;
+        let data: Vec<f32> = (1..=9).map(|i| i as f32).collect();
+        let result = pending.push(&data, Info::default(), 4, |_, _| {
+            calls += 1;
+            if calls == 2 {
+                Err(())
+            } else {
+                Ok(())
+            }
+        });
+        assert_eq!(result, Err(()));
+        // the failed block counts as handed out too
+        assert_eq!(pending.samples, [9.0]);
+    }
+
+    fn plain_wave_format(
+        format_tag: u16,
+        channels: u16,
+        sample_rate: u32,
+        bits_per_sample: u16,
+    ) -> WAVEFORMATEX {
+        let block_align = channels * bits_per_sample / 8;
+        WAVEFORMATEX {
+            wFormatTag: format_tag,
+            nChannels: channels,
+            nSamplesPerSec: sample_rate,
+            nAvgBytesPerSec: sample_rate * block_align as u32,
+            nBlockAlign: block_align,
+            wBitsPerSample: bits_per_sample,
+            cbSize: 0,
+        }
+    }
+
+    fn format_of(wave_format: &WAVEFORMATEX) -> Result<Format, UnknownFormat> {
+        Format::from_wave_format_view(unsafe {
+            WaveFormatView::new(wave_format)
+        })
+    }
+
+    #[test]
+    fn zero_channel_format_is_rejected() {
+        let wave_format =
+            plain_wave_format(WAVE_FORMAT_IEEE_FLOAT, 0, 48000, 32);
+        let error = format_of(&wave_format).unwrap_err();
+        assert_eq!(error.channels, 0);
+        assert_eq!(error.to_string(), "invalid format (zero channels)");
+    }
+
+    #[test]
+    fn zero_rate_format_is_rejected() {
+        let wave_format = plain_wave_format(WAVE_FORMAT_PCM, 2, 0, 16);
+        let error = format_of(&wave_format).unwrap_err();
+        assert_eq!(error.sample_rate, 0);
+        assert_eq!(error.to_string(), "invalid format (zero sample rate)");
+    }
+
+    #[test]
+    fn plain_multichannel_float_format() {
+        let wave_format =
+            plain_wave_format(WAVE_FORMAT_IEEE_FLOAT, 6, 48000, 32);
+        assert_eq!(
+            format_of(&wave_format).unwrap(),
+            Format {
+                channels: 6,
+                sample_rate: 48000,
+                sample_format: SampleFormat::Float32,
+            }
+        );
+    }
+
+    thread_local! {
+        /// Origins `FakeStream::open` was called with on this thread
+        static OPENED: RefCell<Vec<Origin>> = const { RefCell::new(Vec::new()) };
+        /// How many of the next opens fail
+        static FAILING_OPENS: Cell<u32> = const { Cell::new(0) };
+    }
+
+    /// Stands in for WASAPI, recording which options streams are opened
+    /// with.
+    struct FakeStream {
+        origin: Origin,
+        running: bool,
+        fail_start: bool,
+    }
+
+    impl FakeStream {
+        fn running(config: CaptureConfig) -> Self {
+            OPENED.with_borrow_mut(Vec::clear);
+            FAILING_OPENS.set(0);
+            Self {
+                origin: Origin::Config(config),
+                running: true,
+                fail_start: false,
+            }
+        }
+    }
+
+    impl Reopen for FakeStream {
+        fn open(origin: Origin) -> Result<Self, WinError> {
+            OPENED.with_borrow_mut(|opened| opened.push(origin.clone()));
+            let failing = FAILING_OPENS.get();
+            if failing > 0 {
+                FAILING_OPENS.set(failing - 1);
+                return Err(WinError::new(
+                    AUDCLNT_E_DEVICE_INVALIDATED,
+                    "FakeStream::open",
+                ));
+            }
+            Ok(Self {
+                origin,
+                running: false,
+                fail_start: false,
+            })
+        }
+
+        fn origin(&self) -> &Origin {
+            &self.origin
+        }
+
+        fn set_origin(&mut self, origin: Origin) {
+            self.origin = origin;
+        }
+
+        fn replace(&mut self, new: Self) -> Result<(), WinError> {
+            let (was_running, fail_start) = (self.running, self.fail_start);
+            *self = new;
+            if was_running {
+                if fail_start {
+                    return Err(WinError::new(
+                        AUDCLNT_E_DEVICE_INVALIDATED,
+                        "FakeStream::start",
+                    ));
+                }
+                self.running = true;
+            }
+            Ok(())
+        }
+    }
+
+    fn opened() -> Vec<Origin> {
+        OPENED.with_borrow(Vec::clone)
+    }
+
+    #[test]
+    fn reinit_reopens_with_stored_config() {
+        let mut stream = FakeStream::running(target_config());
+        reinit_staged(&mut stream).unwrap();
+        assert_eq!(opened(), [Origin::Config(target_config())]);
+        assert_eq!(stream.origin, Origin::Config(target_config()));
+        assert!(stream.running);
+    }
+
+    #[test]
+    fn set_buffer_duration_reopens_with_stored_config() {
+        let mut stream = FakeStream::running(target_config());
+        let expected = Origin::Config(target_config())
+            .with_buffer_duration(Duration::from_millis(20));
+        set_buffer_duration(&mut stream, Duration::from_millis(20)).unwrap();
+        assert_eq!(stream.origin, expected);
+        assert_eq!(opened(), [expected]);
+    }
+
+    #[test]
+    fn set_buffer_duration_restores_config_if_open_fails() {
+        let mut stream = FakeStream::running(target_config());
+        FAILING_OPENS.set(1);
+        set_buffer_duration(&mut stream, Duration::from_millis(20))
+            .unwrap_err();
+        assert_eq!(stream.origin, Origin::Config(target_config()));
+        assert!(stream.running);
+    }
+
+    #[test]
+    fn set_buffer_duration_keeps_config_if_restart_fails() {
+        let mut stream = FakeStream::running(target_config());
+        stream.fail_start = true;
+        set_buffer_duration(&mut stream, Duration::from_millis(20))
+            .unwrap_err();
+        // the new stream is open, just not running
+        let Origin::Config(config) = &stream.origin else {
+            panic!("origin changed kind");
+        };
+        assert_eq!(config.buffer_duration, Duration::from_millis(20));
+        assert!(!stream.running);
+    }
+
+    #[test]
+    fn auto_reinit_retries_with_stored_config() {
+        let config = CaptureConfig {
+            auto_reinit: 3,
+            ..target_config()
+        };
+        let mut stream = FakeStream::running(config.clone());
+        FAILING_OPENS.set(1);
+        let error = WinError::new(AUDCLNT_E_DEVICE_INVALIDATED, "test");
+        auto_reinit(&mut stream, error, &mut 0).unwrap();
+        assert_eq!(
+            opened(),
+            [Origin::Config(config.clone()), Origin::Config(config)]
+        );
+        assert!(stream.running);
+    }
+
+    #[test]
+    fn auto_reinit_gives_up_after_configured_attempts() {
+        let config = CaptureConfig {
+            auto_reinit: 2,
+            ..target_config()
+        };
+        let mut stream = FakeStream::running(config);
+        FAILING_OPENS.set(u32::MAX);
+        let error = WinError::new(AUDCLNT_E_DEVICE_INVALIDATED, "test");
+        let mut attempt = 0;
+        auto_reinit(&mut stream, error, &mut attempt).unwrap_err();
+        assert_eq!(attempt, 2);
+        assert_eq!(opened().len(), 2);
+    }
+
+    #[test]
+    fn gain_then_clamp() {
+        let mut samples = [0.5, -0.75, 0.25];
+        apply_gain(&mut samples, 2.0, true);
+        assert_eq!(samples, [1.0, -1.0, 0.5]);
+
+        let mut samples = [0.5, -0.75];
+        apply_gain(&mut samples, 2.0, false);
+        assert_eq!(samples, [1.0, -1.5]);
+        apply_gain(&mut samples, 1.0, true);
+        assert_eq!(samples, [1.0, -1.0]);
+    }
+}
@@ -1,91 +1,521 @@
 use core::fmt;
-use std::ptr::null_mut;
+use std::{
+    marker::PhantomData, mem::size_of, panic::Location, ptr::null_mut,
+    time::Duration,
+};
 
+use bitflags::bitflags;
 use winapi::{
     shared::{
         guiddef,
-        ksmedia::{KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_PCM},
-        winerror::S_OK,
+        ksmedia::{
+            KSAUDIO_SPEAKER_5POINT1, KSAUDIO_SPEAKER_7POINT1_SURROUND,
+            KSAUDIO_SPEAKER_MONO, KSAUDIO_SPEAKER_QUAD, KSAUDIO_SPEAKER_STEREO,
+            KSDATAFORMAT_SUBTYPE_ADPCM, KSDATAFORMAT_SUBTYPE_ALAW,
+            KSDATAFORMAT_SUBTYPE_IEEE_FLOAT, KSDATAFORMAT_SUBTYPE_MULAW,
+            KSDATAFORMAT_SUBTYPE_PCM,
+        },
+        mmreg::{WAVEFORMATEX, WAVEFORMATEXTENSIBLE, WAVE_FORMAT_EXTENSIBLE},
+        winerror::{
+            ERROR_OLD_WIN_VERSION, E_INVALIDARG, HRESULT_FROM_WIN32, S_OK,
+        },
+        wtypes::{VT_BOOL, VT_CLSID, VT_EMPTY, VT_LPWSTR, VT_UI4},
     },
-    um::winbase::{
-        FormatMessageA, LocalFree, FORMAT_MESSAGE_ALLOCATE_BUFFER,
-        FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+    um::{
+        audiosessiontypes::{
+            AUDCLNT_STREAMFLAGS_CROSSPROCESS,
+            AUDCLNT_STREAMFLAGS_EVENTCALLBACK, AUDCLNT_STREAMFLAGS_LOOPBACK,
+            AUDCLNT_STREAMFLAGS_NOPERSIST, AUDCLNT_STREAMFLAGS_RATEADJUST,
+        },
+        libloaderapi::{GetModuleHandleW, GetProcAddress},
+        propidl::PROPVARIANT,
+        winbase::{
+            FormatMessageW, LocalFree, FORMAT_MESSAGE_ALLOCATE_BUFFER,
+            FORMAT_MESSAGE_FROM_SYSTEM, FORMAT_MESSAGE_IGNORE_INSERTS,
+        },
+        winnt::OSVERSIONINFOW,
     },
 };
 
-#[macro_export]
+/// Reads a possibly unaligned field of `*$v`, only meant for the packed
+/// structs behind [`WaveFormatView`].
 macro_rules! read_unaligned {
     ($v:ident $(. $field:ident)*) => {
         std::ptr::addr_of!((*$v) $(.$field)* ).read_unaligned()
     };
 }
 
-pub struct WinError(pub i32);
+/// Borrowed `WAVEFORMATEX` with safe accessors, keeping the unaligned
+/// reads of its packed fields in one place.
+#[derive(Clone, Copy)]
+pub struct WaveFormatView<'a> {
+    ptr: *const WAVEFORMATEX,
+    _marker: PhantomData<&'a WAVEFORMATEX>,
+}
+
+impl<'a> WaveFormatView<'a> {
+    /// # Safety
+    /// `ptr` must point to a valid `WAVEFORMATEX` that outlives `'a`, and
+    /// to a whole `WAVEFORMATEXTENSIBLE` if its `wFormatTag` and `cbSize`
+    /// say so.
+    pub unsafe fn new(ptr: *const WAVEFORMATEX) -> Self {
+        Self {
+            ptr,
+            _marker: PhantomData,
+        }
+    }
+
+    pub fn format_tag(&self) -> u16 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.wFormatTag) }
+    }
+
+    pub fn channels(&self) -> u16 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.nChannels) }
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.nSamplesPerSec) }
+    }
+
+    pub fn avg_bytes_per_sec(&self) -> u32 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.nAvgBytesPerSec) }
+    }
+
+    pub fn block_align(&self) -> u16 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.nBlockAlign) }
+    }
+
+    pub fn bits_per_sample(&self) -> u16 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.wBitsPerSample) }
+    }
+
+    /// Size of the extra data following the `WAVEFORMATEX`
+    pub fn extra_size(&self) -> u16 {
+        let ptr = self.ptr;
+        unsafe { read_unaligned!(ptr.cbSize) }
+    }
+
+    /// Whether this is a complete `WAVEFORMATEXTENSIBLE`
+    pub fn is_extensible(&self) -> bool {
+        self.format_tag() == WAVE_FORMAT_EXTENSIBLE
+            && self.extra_size() as usize
+                == size_of::<WAVEFORMATEXTENSIBLE>() - size_of::<WAVEFORMATEX>()
+    }
+
+    /// `SubFormat` of an extensible format
+    pub fn sub_format(&self) -> Option<Guid> {
+        let ptr = self.ptr as *const WAVEFORMATEXTENSIBLE;
+        self.is_extensible()
+            .then(|| unsafe { read_unaligned!(ptr.SubFormat) }.into())
+    }
+
+    /// `dwChannelMask` of an extensible format
+    pub fn channel_mask(&self) -> Option<u32> {
+        let ptr = self.ptr as *const WAVEFORMATEXTENSIBLE;
+        self.is_extensible()
+            .then(|| unsafe { read_unaligned!(ptr.dwChannelMask) })
+    }
+}
+
+pub struct WinError {
+    pub hresult: i32,
+    /// Name of the call that failed, e.g. `"IAudioClient::Initialize"`
+    pub context: &'static str,
+    pub location: &'static Location<'static>,
+}
+
+impl WinError {
+    #[track_caller]
+    pub fn new(hresult: i32, context: &'static str) -> Self {
+        Self {
+            hresult,
+            context,
+            location: Location::caller(),
+        }
+    }
+
+    /// Error for features the running version of Windows lacks, `context`
+    /// should say what's needed and what to use instead.
+    #[track_caller]
+    pub fn unsupported(context: &'static str) -> Self {
+        Self::new(HRESULT_FROM_WIN32(ERROR_OLD_WIN_VERSION), context)
+    }
+
+    /// Whether this came from [`unsupported`](Self::unsupported)
+    pub fn is_unsupported(&self) -> bool {
+        self.hresult == HRESULT_FROM_WIN32(ERROR_OLD_WIN_VERSION)
+    }
+
+    /// Severity bit of the `HRESULT`, `1` for failures and `0` otherwise.
+    pub fn severity(&self) -> u8 {
+        (self.hresult as u32 >> 31) as u8
+    }
+
+    /// Facility the error came from, e.g. `0x889` (`FACILITY_AUDCLNT`) for
+    /// WASAPI errors or `7` (`FACILITY_WIN32`) for wrapped Win32 codes.
+    pub fn facility(&self) -> u16 {
+        ((self.hresult as u32 >> 16) & 0x1fff) as u16
+    }
+
+    /// Facility-specific error code.
+    pub fn code(&self) -> u16 {
+        self.hresult as u32 as u16
+    }
+}
 
 impl fmt::Debug for WinError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "WinError(id: {:x}, {})", self.0, error_to_string(self.0))
+        write!(
+            f,
+            "WinError(id: {:x}, {} at {}, {})",
+            self.hresult,
+            self.context,
+            self.location,
+            error_to_string(self.hresult)
+        )
     }
 }
 
 impl fmt::Display for WinError {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}", error_to_string(self.0))
+        write!(f, "{}: {}", self.context, error_to_string(self.hresult))
     }
 }
 
 impl std::error::Error for WinError {}
 
 #[track_caller]
-pub fn winapi_result(hresult: i32) -> Result<(), WinError> {
+pub fn winapi_result(
+    hresult: i32,
+    context: &'static str,
+) -> Result<(), WinError> {
     if hresult == S_OK {
         Ok(())
     } else {
-        Err(WinError(hresult))
+        Err(WinError::new(hresult, context))
+    }
+}
+
+/// Usual speaker layout for `channels` channels, 0 (no particular layout)
+/// for counts without one.
+pub fn default_channel_mask(channels: u16) -> u32 {
+    match channels {
+        1 => KSAUDIO_SPEAKER_MONO,
+        2 => KSAUDIO_SPEAKER_STEREO,
+        4 => KSAUDIO_SPEAKER_QUAD,
+        6 => KSAUDIO_SPEAKER_5POINT1,
+        8 => KSAUDIO_SPEAKER_7POINT1_SURROUND,
+        _ => 0,
+    }
+}
+
+/// Converts `duration` to a `REFERENCE_TIME`, in 100ns units as used
+/// throughout WASAPI. Sub-100ns precision is truncated.
+pub fn duration_to_reference_time(
+    duration: Duration,
+) -> Result<i64, DurationOverflow> {
+    i64::try_from(duration.as_nanos() / 100).map_err(|_| DurationOverflow)
+}
+
+/// Converts a `REFERENCE_TIME` to a [`Duration`], negative values mean
+/// zero.
+pub fn reference_time_to_duration(reference_time: i64) -> Duration {
+    let reference_time = reference_time.max(0) as u64;
+    Duration::new(
+        reference_time / 10_000_000,
+        (reference_time % 10_000_000) as u32 * 100,
+    )
+}
+
+/// Duration too long to express as a `REFERENCE_TIME`, about 29000 years
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub struct DurationOverflow;
+
+impl fmt::Display for DurationOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "duration doesn't fit in a REFERENCE_TIME")
+    }
+}
+
+impl std::error::Error for DurationOverflow {}
+
+impl From<DurationOverflow> for WinError {
+    #[track_caller]
+    fn from(_: DurationOverflow) -> Self {
+        WinError::new(E_INVALIDARG, "duration_to_reference_time")
+    }
+}
+
+/// Build number of the running Windows, e.g. 19041 for Windows 10 2004.
+///
+/// Asks `RtlGetVersion` directly, since `GetVersionEx` and
+/// `VerifyVersionInfo` report Windows 8 to applications without a
+/// compatibility manifest.
+pub fn os_build() -> Option<u32> {
+    type RtlGetVersion = unsafe extern "system" fn(*mut OSVERSIONINFOW) -> i32;
+
+    let ntdll: Vec<u16> = "ntdll.dll\0".encode_utf16().collect();
+    unsafe {
+        let module = GetModuleHandleW(ntdll.as_ptr());
+        if module.is_null() {
+            return None;
+        }
+        let proc = GetProcAddress(module, c"RtlGetVersion".as_ptr());
+        if proc.is_null() {
+            return None;
+        }
+        let rtl_get_version: RtlGetVersion = std::mem::transmute(proc);
+        let mut info: OSVERSIONINFOW = std::mem::zeroed();
+        info.dwOSVersionInfoSize = size_of::<OSVERSIONINFOW>() as _;
+        // STATUS_SUCCESS
+        (rtl_get_version(&mut info) == 0).then_some(info.dwBuildNumber)
     }
 }
 
 fn error_to_string(code: i32) -> String {
-    let mut buffer: *mut i8 = null_mut();
+    let mut buffer: *mut u16 = null_mut();
     unsafe {
-        let size = FormatMessageA(
+        let size = FormatMessageW(
             FORMAT_MESSAGE_ALLOCATE_BUFFER
                 | FORMAT_MESSAGE_FROM_SYSTEM
                 | FORMAT_MESSAGE_IGNORE_INSERTS,
             null_mut(),
             code as u32,
             0,
-            &mut buffer as *mut _ as *mut i8,
+            &mut buffer as *mut _ as *mut u16,
             0,
             null_mut(),
         );
-        let slice = std::slice::from_raw_parts(buffer as _, size as usize);
-        let str = std::str::from_utf8(slice).unwrap();
-        let string = str.to_string();
+        // no message for this code, e.g. most AUDCLNT_E_* errors
+        if size == 0 || buffer.is_null() {
+            return format!("HRESULT 0x{:08X}", code as u32);
+        }
+        let slice = std::slice::from_raw_parts(buffer, size as usize);
+        let string = String::from_utf16_lossy(slice);
         LocalFree(buffer as _);
         string
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(PartialEq, Eq, Clone, Copy)]
 pub struct Guid(u32, u16, u16, [u8; 8]);
 
+impl fmt::Debug for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+impl fmt::Display for Guid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Self(data1, data2, data3, data4) = self;
+        write!(
+            f,
+            "{{{:08X}-{:04X}-{:04X}-{:02X}{:02X}-",
+            data1, data2, data3, data4[0], data4[1]
+        )?;
+        for byte in &data4[2..] {
+            write!(f, "{:02X}", byte)?;
+        }
+        write!(f, "}}")
+    }
+}
+
 impl Guid {
     pub const fn from_winapi(guid: guiddef::GUID) -> Self {
         Self(guid.Data1, guid.Data2, guid.Data3, guid.Data4)
     }
 }
 
+impl Guid {
+    pub fn known_subtype(&self) -> Option<KnownSubtype> {
+        Some(match *self {
+            DATAFORMAT_SUBTYPE_PCM => KnownSubtype::Pcm,
+            DATAFORMAT_SUBTYPE_IEEE_FLOAT => KnownSubtype::IeeeFloat,
+            DATAFORMAT_SUBTYPE_ADPCM => KnownSubtype::Adpcm,
+            DATAFORMAT_SUBTYPE_ALAW => KnownSubtype::Alaw,
+            DATAFORMAT_SUBTYPE_MULAW => KnownSubtype::Mulaw,
+            DATAFORMAT_SUBTYPE_IEC61937_DTS => KnownSubtype::Dts,
+            _ => return None,
+        })
+    }
+}
+
 impl From<guiddef::GUID> for Guid {
     fn from(guid: guiddef::GUID) -> Self {
         Self::from_winapi(guid)
     }
 }
 
-pub const _AUDCLNT_STREAMFLAGS_AUTOCONVERTPCM: u32 = 0x80000000;
-pub const _AUDCLNT_STREAMFLAGS_SRC_DEFAULT_QUALITY: u32 = 0x08000000;
+bitflags! {
+    /// `AUDCLNT_STREAMFLAGS_*` passed to `IAudioClient::Initialize`.
+    ///
+    /// For loopback capture `LOOPBACK` has to be set and the endpoint has to
+    /// be a render device. `EVENTCALLBACK` together with `LOOPBACK` only
+    /// signals events since Windows 10. `SRC_DEFAULT_QUALITY` has no effect
+    /// without `AUTOCONVERTPCM`.
+    #[derive(Debug, PartialEq, Eq, Clone, Copy, Default)]
+    pub struct StreamFlags: u32 {
+        const CROSSPROCESS = AUDCLNT_STREAMFLAGS_CROSSPROCESS;
+        const LOOPBACK = AUDCLNT_STREAMFLAGS_LOOPBACK;
+        const EVENTCALLBACK = AUDCLNT_STREAMFLAGS_EVENTCALLBACK;
+        const NOPERSIST = AUDCLNT_STREAMFLAGS_NOPERSIST;
+        const RATEADJUST = AUDCLNT_STREAMFLAGS_RATEADJUST;
+        // not in winapi
+        const SRC_DEFAULT_QUALITY = 0x08000000;
+        const AUTOCONVERTPCM = 0x80000000;
+    }
+}
+
+/// `WAVEFORMATEXTENSIBLE::SubFormat` values this crate can name
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum KnownSubtype {
+    Pcm,
+    IeeeFloat,
+    Adpcm,
+    Alaw,
+    Mulaw,
+    Dts,
+}
 
 pub const DATAFORMAT_SUBTYPE_PCM: Guid =
     Guid::from_winapi(KSDATAFORMAT_SUBTYPE_PCM);
 pub const DATAFORMAT_SUBTYPE_IEEE_FLOAT: Guid =
     Guid::from_winapi(KSDATAFORMAT_SUBTYPE_IEEE_FLOAT);
+pub const DATAFORMAT_SUBTYPE_ADPCM: Guid =
+    Guid::from_winapi(KSDATAFORMAT_SUBTYPE_ADPCM);
+pub const DATAFORMAT_SUBTYPE_ALAW: Guid =
+    Guid::from_winapi(KSDATAFORMAT_SUBTYPE_ALAW);
+pub const DATAFORMAT_SUBTYPE_MULAW: Guid =
+    Guid::from_winapi(KSDATAFORMAT_SUBTYPE_MULAW);
+// not in winapi, WAVE_FORMAT_DTS on the usual subtype base
+pub const DATAFORMAT_SUBTYPE_IEC61937_DTS: Guid = Guid(
+    0x00000008,
+    0x0000,
+    0x0010,
+    [0x80, 0x00, 0x00, 0xaa, 0x00, 0x38, 0x9b, 0x71],
+);
+
+// commonly used keys for `AudioCapture::device_property`
+pub use winapi::{
+    shared::wtypes::PROPERTYKEY,
+    um::{
+        functiondiscoverykeys_devpkey::{
+            PKEY_DeviceInterface_FriendlyName, PKEY_Device_DeviceDesc,
+            PKEY_Device_FriendlyName,
+        },
+        mmdeviceapi::{
+            PKEY_AudioEndpoint_FormFactor, PKEY_AudioEndpoint_GUID,
+            PKEY_AudioEndpoint_JackSubType,
+            PKEY_AudioEndpoint_PhysicalSpeakers,
+        },
+    },
+};
+
+/// Owned copy of a `PROPVARIANT` device property
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum PropValue {
+    /// Property isn't set
+    Empty,
+    String(String),
+    U32(u32),
+    Bool(bool),
+    Guid(Guid),
+    /// Any other variant type, identified by its `VARTYPE`
+    Unsupported(u16),
+}
+
+impl PropValue {
+    /// Copies the value out of `prop`, which stays owned by the caller.
+    ///
+    /// # Safety
+    /// `prop` must be a valid, initialized `PROPVARIANT`.
+    pub(crate) unsafe fn from_propvariant(prop: &PROPVARIANT) -> Self {
+        match prop.vt as u32 {
+            VT_EMPTY => Self::Empty,
+            VT_LPWSTR => {
+                let ptr = *prop.data.pwszVal();
+                if ptr.is_null() {
+                    return Self::String(String::new());
+                }
+                let len = (0..).take_while(|&i| *ptr.add(i) != 0).count();
+                let wide = std::slice::from_raw_parts(ptr, len);
+                Self::String(String::from_utf16_lossy(wide))
+            }
+            VT_UI4 => Self::U32(*prop.data.ulVal()),
+            // VARIANT_TRUE is -1
+            VT_BOOL => Self::Bool(*prop.data.boolVal() != 0),
+            VT_CLSID => {
+                let ptr = *prop.data.puuid();
+                if ptr.is_null() {
+                    Self::Empty
+                } else {
+                    Self::Guid(Guid::from_winapi(*ptr))
+                }
+            }
+            vt => Self::Unsupported(vt as u16),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Longest duration that still fits in a `REFERENCE_TIME`
+    fn max_reference_duration() -> Duration {
+        Duration::new(
+            (i64::MAX / 10_000_000) as u64,
+            (i64::MAX % 10_000_000) as u32 * 100,
+        )
+    }
+
+    #[test]
+    fn reference_time_units() {
+        let duration = Duration::from_millis(10);
+        assert_eq!(duration_to_reference_time(duration), Ok(100_000));
+        assert_eq!(reference_time_to_duration(100_000), duration);
+        // sub-100ns precision is truncated
+        assert_eq!(
+            duration_to_reference_time(Duration::from_nanos(199)),
+            Ok(1)
+        );
+    }
+
+    #[test]
+    fn reference_time_large_durations() {
+        // a thousand years
+        let duration = Duration::from_secs(1000 * 365 * 24 * 60 * 60);
+        let reference_time = duration_to_reference_time(duration).unwrap();
+        assert_eq!(reference_time, duration.as_secs() as i64 * 10_000_000);
+        assert_eq!(reference_time_to_duration(reference_time), duration);
+    }
+
+    #[test]
+    fn reference_time_overflow_boundary() {
+        let max = max_reference_duration();
+        assert_eq!(duration_to_reference_time(max), Ok(i64::MAX));
+        assert_eq!(reference_time_to_duration(i64::MAX), max);
+        // still truncated into the last unit
+        let truncated = max + Duration::from_nanos(99);
+        assert_eq!(duration_to_reference_time(truncated), Ok(i64::MAX));
+        let over = max + Duration::from_nanos(100);
+        assert_eq!(duration_to_reference_time(over), Err(DurationOverflow));
+        assert_eq!(
+            duration_to_reference_time(Duration::MAX),
+            Err(DurationOverflow)
+        );
+    }
+
+    #[test]
+    fn negative_reference_time_is_zero() {
+        assert_eq!(reference_time_to_duration(-1), Duration::ZERO);
+        assert_eq!(reference_time_to_duration(i64::MIN), Duration::ZERO);
+    }
+}
@@ -0,0 +1,193 @@
+use std::collections::HashMap;
+
+use crate::Format;
+
+use super::{
+    capture::{Capture, Info, ReadSamplesError, UnknownFormat},
+    common::WinError,
+};
+
+/// [`Capture`] that plays back a prepared buffer instead of recording, for
+/// tests that can't rely on WASAPI being around.
+///
+/// Every [`read_samples`](Capture::read_samples) call while started hands
+/// out the next packet of `packet_frames` frames (the last one may be
+/// shorter), as if one device period passed between calls.
+pub struct MockCapture {
+    format: Format,
+    samples: Vec<f32>,
+    packet_frames: usize,
+    /// Flags for specific packets, by index, all others are clean
+    infos: HashMap<usize, Info>,
+    next_packet: usize,
+    running: bool,
+}
+
+impl MockCapture {
+    /// `samples` are interleaved according to `format`.
+    pub fn new(
+        format: Format,
+        samples: Vec<f32>,
+        packet_frames: usize,
+    ) -> Self {
+        assert!(packet_frames > 0, "packets need at least one frame");
+        // packets would be empty and the mock would never finish
+        assert!(format.channels > 0, "format needs at least one channel");
+        Self {
+            format,
+            samples,
+            packet_frames,
+            infos: HashMap::new(),
+            next_packet: 0,
+            running: false,
+        }
+    }
+
+    /// Reports `info` for the packet with index `packet`.
+    pub fn set_packet_info(&mut self, packet: usize, info: Info) {
+        self.infos.insert(packet, info);
+    }
+
+    /// Whether every packet has been read.
+    pub fn is_finished(&self) -> bool {
+        self.next_packet * self.packet_samples() >= self.samples.len()
+    }
+
+    fn packet_samples(&self) -> usize {
+        self.packet_frames * self.format.channels as usize
+    }
+}
+
+impl Capture for MockCapture {
+    fn format(&self) -> Result<Format, UnknownFormat> {
+        Ok(self.format)
+    }
+
+    fn start(&mut self) -> Result<(), WinError> {
+        self.running = true;
+        Ok(())
+    }
+
+    fn stop(&mut self) -> Result<(), WinError> {
+        self.running = false;
+        Ok(())
+    }
+
+    fn read_samples<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32], Info) -> Result<(), E>,
+    {
+        if !self.running || self.is_finished() {
+            return Ok(());
+        }
+        let start = self.next_packet * self.packet_samples();
+        let end = (start + self.packet_samples()).min(self.samples.len());
+        let info = self
+            .infos
+            .get(&self.next_packet)
+            .copied()
+            .unwrap_or_default();
+        self.next_packet += 1;
+        f(&self.samples[start..end], info).map_err(ReadSamplesError::E)
+    }
+}
+
+#[cfg(all(test, feature = "mock"))]
+mod tests {
+    use std::convert::Infallible;
+
+    use crate::SampleFormat;
+
+    use super::*;
+
+    const STEREO: Format = Format {
+        channels: 2,
+        sample_rate: 48000,
+        sample_format: SampleFormat::Float32,
+    };
+
+    /// Reads every packet, with the mock started
+    fn packets(mock: &mut MockCapture) -> Vec<(Vec<f32>, Info)> {
+        let mut packets = Vec::new();
+        mock.start().unwrap();
+        while !mock.is_finished() {
+            mock.read_samples(|data, info| {
+                packets.push((data.to_vec(), info));
+                Ok::<_, Infallible>(())
+            })
+            .unwrap();
+        }
+        packets
+    }
+
+    #[test]
+    fn packet_boundaries() {
+        // 5 stereo frames in packets of 2, the last one short
+        let samples: Vec<f32> = (0..10).map(|i| i as f32).collect();
+        let mut mock = MockCapture::new(STEREO, samples, 2);
+        let data: Vec<_> =
+            packets(&mut mock).into_iter().map(|p| p.0).collect();
+        assert_eq!(
+            data,
+            [
+                vec![0.0, 1.0, 2.0, 3.0],
+                vec![4.0, 5.0, 6.0, 7.0],
+                vec![8.0, 9.0],
+            ]
+        );
+        // nothing left once finished
+        let mut calls = 0;
+        mock.read_samples(|_, _| {
+            calls += 1;
+            Ok::<_, Infallible>(())
+        })
+        .unwrap();
+        assert_eq!(calls, 0);
+    }
+
+    #[test]
+    fn packet_flags() {
+        let silent = Info {
+            is_silent: true,
+            ..Info::default()
+        };
+        let mut mock = MockCapture::new(STEREO, vec![0.5; 12], 2);
+        mock.set_packet_info(1, silent);
+        let infos: Vec<_> =
+            packets(&mut mock).into_iter().map(|p| p.1).collect();
+        assert_eq!(infos, [Info::default(), silent, Info::default()]);
+    }
+
+    #[test]
+    fn reads_nothing_until_started() {
+        let mut mock = MockCapture::new(STEREO, vec![0.5; 4], 1);
+        let mut calls = 0;
+        let mut read = |mock: &mut MockCapture| {
+            mock.read_samples(|_, _| {
+                calls += 1;
+                Ok::<_, Infallible>(())
+            })
+            .unwrap()
+        };
+        read(&mut mock);
+        mock.start().unwrap();
+        read(&mut mock);
+        mock.stop().unwrap();
+        read(&mut mock);
+        assert_eq!(calls, 1);
+        assert!(!mock.is_finished());
+    }
+
+    #[test]
+    #[should_panic(expected = "at least one channel")]
+    fn rejects_zero_channels() {
+        let format = Format {
+            channels: 0,
+            ..STEREO
+        };
+        MockCapture::new(format, vec![0.5; 4], 1);
+    }
+}
@@ -1,2 +1,6 @@
+mod activate;
 pub mod capture;
 pub mod common;
+#[cfg(feature = "mock")]
+pub mod mock;
+pub mod multi;
@@ -0,0 +1,133 @@
+use std::{collections::VecDeque, convert::Infallible};
+
+use crate::{
+    dsp::{mix, MixMode, Transcoder},
+    Format,
+};
+
+use super::{
+    capture::{AudioCapture, ReadSamplesError, UnknownFormat},
+    common::WinError,
+};
+
+struct Source {
+    capture: AudioCapture,
+    transcoder: Transcoder,
+    /// Samples already converted to the target format, waiting for the other
+    /// sources to catch up
+    queue: VecDeque<f32>,
+}
+
+/// Captures several endpoints at once and mixes them into a single stream.
+///
+/// Every source is remixed and resampled to a common target format, and
+/// mixed output is only produced for frames that all sources have delivered.
+/// A loopback source with nothing playing may stop delivering packets, which
+/// stalls the mix, so enable
+/// [`keepalive_render`](super::capture::CaptureConfig::keepalive_render) for
+/// those.
+pub struct MultiCapture {
+    sources: Vec<Source>,
+    format: Format,
+    mix_mode: MixMode,
+}
+
+impl MultiCapture {
+    /// `format` is the layout and rate of the mixed output, its sample format
+    /// is ignored since mixing always happens in `f32`.
+    pub fn new(
+        captures: Vec<AudioCapture>,
+        format: Format,
+    ) -> Result<Self, UnknownFormat> {
+        let sources = captures
+            .into_iter()
+            .map(|capture| {
+                let transcoder = Transcoder::new(&capture.format()?, &format);
+                Ok(Source {
+                    capture,
+                    transcoder,
+                    queue: VecDeque::new(),
+                })
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self {
+            sources,
+            format,
+            mix_mode: MixMode::default(),
+        })
+    }
+
+    pub fn format(&self) -> Format {
+        self.format
+    }
+
+    pub fn set_mix_mode(&mut self, mix_mode: MixMode) {
+        self.mix_mode = mix_mode;
+    }
+
+    pub fn start(&mut self) -> Result<(), WinError> {
+        for source in &mut self.sources {
+            source.capture.start()?;
+        }
+        Ok(())
+    }
+
+    pub fn stop(&mut self) -> Result<(), WinError> {
+        for source in &mut self.sources {
+            source.capture.stop()?;
+        }
+        Ok(())
+    }
+
+    /// Reads every source and runs `f` with the mix of all frames that are
+    /// available from each of them. Like
+    /// [`AudioCapture::read_samples`], this needs to be called in a loop.
+    pub fn read_mixed<E, F>(
+        &mut self,
+        mut f: F,
+    ) -> Result<(), ReadSamplesError<E>>
+    where
+        F: FnMut(&[f32]) -> Result<(), E>,
+    {
+        let channels = self.format.channels;
+        for source in &mut self.sources {
+            let Source {
+                capture,
+                transcoder,
+                queue,
+            } = source;
+            capture
+                .read_samples::<Infallible, _>(|data, info| {
+                    let samples = if info.is_silent {
+                        transcoder.process(&vec![0.0; data.len()])
+                    } else {
+                        transcoder.process(data)
+                    };
+                    queue.extend(samples);
+                    Ok(())
+                })
+                .map_err(ReadSamplesError::into_win_error)?;
+        }
+
+        let available = self
+            .sources
+            .iter()
+            .map(|source| source.queue.len())
+            .min()
+            .unwrap_or(0);
+        let available = available - available % channels.max(1) as usize;
+        if available == 0 {
+            return Ok(());
+        }
+        let streams: Vec<&[f32]> = self
+            .sources
+            .iter_mut()
+            .map(|source| &source.queue.make_contiguous()[..available])
+            .collect();
+        let mixed = mix(&streams, self.mix_mode);
+        for source in &mut self.sources {
+            source.queue.drain(..available);
+        }
+        f(&mixed).map_err(ReadSamplesError::E)
+    }
+}